@@ -0,0 +1,332 @@
+//! Beam search decoding.
+
+use crate::config::LlamaConfig;
+use crate::error::{LlamaError, Result};
+use crate::model::forward;
+use crate::ops::log_softmax;
+use crate::state::LlamaState;
+use crate::weights::LlamaWeights;
+
+/// Beam search parameters.
+#[derive(Debug, Clone)]
+pub struct BeamConfig {
+    /// Number of beams kept per step
+    pub width: usize,
+    /// Exponent applied to the generated length when scoring finished beams
+    pub length_penalty: f32,
+    /// Stop as soon as `width` hypotheses have finished
+    pub early_stopping: bool,
+    /// Number of hypotheses returned
+    pub n_best: usize,
+    /// Maximum number of generated tokens
+    pub max_tokens: usize,
+    /// Tokens that finish a hypothesis
+    pub eos_tokens: Vec<i32>,
+}
+
+impl Default for BeamConfig {
+    fn default() -> Self {
+        BeamConfig {
+            width: 4,
+            length_penalty: 1.0,
+            early_stopping: false,
+            n_best: 1,
+            max_tokens: 256,
+            eos_tokens: vec![2],
+        }
+    }
+}
+
+/// A finished (or truncated) beam.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hypothesis {
+    /// Generated tokens, including the final EOS if there is one
+    pub tokens: Vec<i32>,
+    /// Sum of token log-probabilities
+    pub log_prob: f32,
+    /// `log_prob / len^length_penalty`
+    pub score: f32,
+}
+
+struct Beam {
+    tokens: Vec<i32>,
+    log_prob: f32,
+}
+
+impl BeamConfig {
+    fn score(&self, log_prob: f32, len: usize) -> f32 {
+        log_prob / (len.max(1) as f32).powf(self.length_penalty)
+    }
+
+    /// Run beam search after `prompt`, returning up to `n_best` hypotheses, best first.
+    ///
+    /// `state` must be freshly allocated; its cache policy is shared by all beams.
+    pub fn search(
+        &self,
+        prompt: &[i32],
+        config: &LlamaConfig,
+        weights: &LlamaWeights,
+        state: LlamaState,
+    ) -> Result<Vec<Hypothesis>> {
+        if self.width == 0 || self.n_best == 0 || self.n_best > self.width {
+            return Err(LlamaError::Config(
+                "beam search needs 0 < n_best <= width".into(),
+            ));
+        }
+        if prompt.is_empty() {
+            return Err(LlamaError::Config("beam search needs a prompt".into()));
+        }
+        let max_pos = if state.cache_policy.evicts() {
+            usize::MAX
+        } else {
+            config.seq_len as usize
+        };
+
+        let mut states = vec![state];
+        for (pos, &token) in prompt.iter().enumerate() {
+            forward(token, pos as i32, config, &mut states[0], weights);
+        }
+        let mut beams = vec![Beam {
+            tokens: Vec::new(),
+            log_prob: 0.0,
+        }];
+        let mut finished: Vec<Hypothesis> = Vec::new();
+        let end = prompt.len().saturating_add(self.max_tokens).min(max_pos);
+
+        for pos in prompt.len()..end {
+            // Top 2*width continuations of each beam, so enough survive EOS
+            let mut cands: Vec<(usize, i32, f32)> = Vec::new();
+            for (b, (beam, state)) in beams.iter().zip(&states).enumerate() {
+                let mut logprobs = state.logits.clone();
+                log_softmax(&mut logprobs);
+                let mut ids: Vec<usize> = (0..logprobs.len()).collect();
+                let k = (2 * self.width).min(ids.len());
+                ids.select_nth_unstable_by(k - 1, |&a, &b| logprobs[b].total_cmp(&logprobs[a]));
+                cands.extend(
+                    ids[..k]
+                        .iter()
+                        .map(|&id| (b, id as i32, beam.log_prob + logprobs[id])),
+                );
+            }
+            cands.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+            let mut next = Vec::with_capacity(self.width);
+            for (rank, &(b, token, log_prob)) in cands.iter().enumerate() {
+                if self.eos_tokens.contains(&token) {
+                    // EOS only counts if it would have made the beam itself
+                    if rank < self.width {
+                        let mut tokens = beams[b].tokens.clone();
+                        tokens.push(token);
+                        self.add(&mut finished, tokens, log_prob);
+                    }
+                } else {
+                    next.push((b, token, log_prob));
+                }
+                if next.len() == self.width {
+                    break;
+                }
+            }
+
+            if self.is_done(
+                &finished,
+                next.first().map(|c| c.2),
+                beams[0].tokens.len() + 1,
+            ) {
+                beams.clear();
+                break;
+            }
+
+            let parents: Vec<usize> = next.iter().map(|c| c.0).collect();
+            reorder(&mut states, &parents);
+            beams = next
+                .iter()
+                .map(|&(b, token, log_prob)| {
+                    let mut tokens = beams[b].tokens.clone();
+                    tokens.push(token);
+                    Beam { tokens, log_prob }
+                })
+                .collect();
+            for (beam, state) in beams.iter().zip(states.iter_mut()) {
+                let token = beam.tokens[beam.tokens.len() - 1];
+                forward(token, pos as i32, config, state, weights);
+            }
+        }
+
+        // Beams still running when the budget ran out compete with finished ones
+        for beam in beams {
+            self.add(&mut finished, beam.tokens, beam.log_prob);
+        }
+        finished.truncate(self.n_best);
+        Ok(finished)
+    }
+
+    /// Insert a hypothesis, keeping at most `width` sorted by score.
+    fn add(&self, finished: &mut Vec<Hypothesis>, tokens: Vec<i32>, log_prob: f32) {
+        let score = self.score(log_prob, tokens.len());
+        let idx = finished.partition_point(|h| h.score >= score);
+        if idx < self.width {
+            finished.insert(
+                idx,
+                Hypothesis {
+                    tokens,
+                    log_prob,
+                    score,
+                },
+            );
+            finished.truncate(self.width);
+        }
+    }
+
+    /// Whether no running beam can still enter the finished list.
+    fn is_done(&self, finished: &[Hypothesis], best_running: Option<f32>, len: usize) -> bool {
+        let Some(best) = best_running else {
+            return true;
+        };
+        if finished.len() < self.width {
+            return false;
+        }
+        if self.early_stopping {
+            return true;
+        }
+        let worst = finished[finished.len() - 1].score;
+        self.score(best, len) <= worst
+    }
+}
+
+/// Rearrange `states` so that entry `i` holds the cache of old entry `parents[i]`.
+///
+/// Each parent's first child takes over its state; further children copy it into
+/// a state whose beam was dropped, so no buffers are reallocated.
+pub fn reorder(states: &mut Vec<LlamaState>, parents: &[usize]) {
+    let mut old: Vec<Option<LlamaState>> = states.drain(..).map(Some).collect();
+    let mut owner = vec![usize::MAX; old.len()];
+    let mut slots: Vec<Option<LlamaState>> = Vec::with_capacity(parents.len());
+    for (i, &p) in parents.iter().enumerate() {
+        slots.push(old[p].take());
+        if owner[p] == usize::MAX {
+            owner[p] = i;
+        }
+    }
+    let mut spare: Vec<LlamaState> = old.into_iter().flatten().collect();
+    for (i, &p) in parents.iter().enumerate() {
+        if slots[i].is_some() {
+            continue;
+        }
+        let Some(src) = slots[owner[p]].as_ref() else {
+            continue;
+        };
+        let state = match spare.pop() {
+            Some(mut state) => {
+                state.copy_cache_from(src);
+                state
+            }
+            None => src.clone(),
+        };
+        slots[i] = Some(state);
+    }
+    states.extend(slots.into_iter().flatten());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> LlamaConfig {
+        LlamaConfig {
+            dim: 16,
+            hidden_dim: 32,
+            n_layers: 2,
+            n_heads: 4,
+            n_kv_heads: 2,
+            vocab_size: 32,
+            seq_len: 16,
+        }
+    }
+
+    fn run(config: &LlamaConfig, weights: &LlamaWeights, tokens: &[i32]) -> LlamaState {
+        let mut state = LlamaState::new(config);
+        for (pos, &token) in tokens.iter().enumerate() {
+            forward(token, pos as i32, config, &mut state, weights);
+        }
+        state
+    }
+
+    #[test]
+    fn reorder_moves_and_copies_parent_caches() {
+        let config = config();
+        let weights = LlamaWeights::random(&config, 3);
+        let prompts: [&[i32]; 3] = [&[1, 2], &[3, 4, 5], &[6]];
+        let mut states: Vec<LlamaState> =
+            prompts.iter().map(|p| run(&config, &weights, p)).collect();
+        let before = states.clone();
+
+        let parents = [1, 1, 0];
+        reorder(&mut states, &parents);
+        assert_eq!(states.len(), parents.len());
+        for (state, &p) in states.iter().zip(&parents) {
+            let src = &before[p];
+            assert_eq!(state.cache_len, src.cache_len);
+            let n = src.cache_len * config.kv_dim();
+            for l in 0..config.n_layers as usize {
+                assert_eq!(state.key_cache[l][..n], src.key_cache[l][..n]);
+                assert_eq!(state.value_cache[l][..n], src.value_cache[l][..n]);
+            }
+        }
+    }
+
+    #[test]
+    fn search_finishes_on_eos_and_normalises_scores() {
+        let config = config();
+        let weights = LlamaWeights::random(&config, 4);
+        let prompt = [1, 5, 9];
+        let mut logprobs = run(&config, &weights, &prompt).logits;
+        log_softmax(&mut logprobs);
+        // Make the most likely first token the end of generation
+        let eos = (0..logprobs.len())
+            .max_by(|&a, &b| logprobs[a].total_cmp(&logprobs[b]))
+            .unwrap() as i32;
+
+        let beam = BeamConfig {
+            width: 3,
+            length_penalty: 0.7,
+            n_best: 3,
+            max_tokens: 5,
+            eos_tokens: vec![eos],
+            ..Default::default()
+        };
+        let hyps = beam
+            .search(&prompt, &config, &weights, LlamaState::new(&config))
+            .unwrap();
+        assert_eq!(hyps.len(), 3);
+
+        let stopped = hyps.iter().find(|h| h.tokens == [eos]).unwrap();
+        assert!((stopped.log_prob - logprobs[eos as usize]).abs() < 1e-4);
+        for h in &hyps {
+            // EOS can only end a hypothesis
+            let body = &h.tokens[..h.tokens.len() - 1];
+            assert!(!body.contains(&eos), "{:?}", h.tokens);
+            assert!(h.tokens.len() <= beam.max_tokens);
+
+            // The log-probability is the sum over a fresh run, and the score
+            // normalises it by length
+            let mut state = run(&config, &weights, &prompt);
+            let mut log_prob = 0.0;
+            for (i, &token) in h.tokens.iter().enumerate() {
+                let mut lp = state.logits.clone();
+                log_softmax(&mut lp);
+                log_prob += lp[token as usize];
+                forward(
+                    token,
+                    (prompt.len() + i) as i32,
+                    &config,
+                    &mut state,
+                    &weights,
+                );
+            }
+            assert!((h.log_prob - log_prob).abs() < 1e-3, "{h:?}");
+            let expected = h.log_prob / (h.tokens.len() as f32).powf(0.7);
+            assert!((h.score - expected).abs() < 1e-5);
+        }
+        assert!(hyps.windows(2).all(|w| w[0].score >= w[1].score));
+    }
+}
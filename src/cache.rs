@@ -0,0 +1,131 @@
+//! KV cache eviction policies.
+
+use crate::error::{LlamaError, Result};
+
+/// Policy deciding which cached positions survive once the KV cache is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Keep every position; generation is bounded by `seq_len`.
+    #[default]
+    Full,
+    /// Attention sinks plus a sliding window of recent tokens (StreamingLLM).
+    ///
+    /// The first `n_sink` tokens are never evicted; once the cache is full the
+    /// oldest token after the sinks is overwritten. Positions are assigned by
+    /// order in the cache rather than by absolute position, so RoPE never
+    /// leaves the trained range.
+    Streaming { n_sink: usize, window: usize },
+    /// Heavy-hitter eviction driven by accumulated attention (H2O).
+    ///
+    /// The cache holds at most `budget` tokens. When full, the token with the
+    /// least attention mass received so far is dropped, except for the
+    /// `n_recent` newest tokens, which have not yet had a chance to collect mass.
+    HeavyHitter { budget: usize, n_recent: usize },
+}
+
+impl CachePolicy {
+    /// Returns the number of KV slots needed for a context of `seq_len`.
+    pub fn capacity(&self, seq_len: usize) -> usize {
+        match *self {
+            CachePolicy::Full => seq_len,
+            CachePolicy::Streaming { n_sink, window } => n_sink + window,
+            CachePolicy::HeavyHitter { budget, .. } => budget,
+        }
+    }
+
+    /// Check the policy against the model context length.
+    pub fn validate(&self, seq_len: usize) -> Result<()> {
+        match *self {
+            CachePolicy::Full => Ok(()),
+            CachePolicy::Streaming { n_sink, window } => {
+                if window == 0 {
+                    return Err(LlamaError::Config(
+                        "streaming cache needs a non-empty window".into(),
+                    ));
+                }
+                if n_sink + window > seq_len {
+                    return Err(LlamaError::Config(format!(
+                        "streaming cache of {} slots exceeds seq_len {}",
+                        n_sink + window,
+                        seq_len
+                    )));
+                }
+                Ok(())
+            }
+            CachePolicy::HeavyHitter { budget, n_recent } => {
+                if n_recent >= budget {
+                    return Err(LlamaError::Config(format!(
+                        "heavy-hitter cache keeps {} recent tokens but budget is {}",
+                        n_recent, budget
+                    )));
+                }
+                if budget > seq_len {
+                    return Err(LlamaError::Config(format!(
+                        "heavy-hitter budget {} exceeds seq_len {}",
+                        budget, seq_len
+                    )));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns true if the policy evicts tokens from a full cache.
+    #[inline]
+    pub fn evicts(&self) -> bool {
+        *self != CachePolicy::Full
+    }
+
+    /// Returns the slot to evict from a full cache, given the attention mass
+    /// accumulated by each occupied slot.
+    pub fn victim(&self, att_mass: &[f32]) -> Option<usize> {
+        match *self {
+            CachePolicy::Full => None,
+            CachePolicy::Streaming { n_sink, .. } => Some(n_sink),
+            CachePolicy::HeavyHitter { n_recent, .. } => {
+                let candidates = att_mass.len().saturating_sub(n_recent);
+                att_mass[..candidates]
+                    .iter()
+                    .enumerate()
+                    .min_by(|a, b| a.1.total_cmp(b.1))
+                    .map(|(i, _)| i)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn victim_skips_sinks_and_recent_tokens() {
+        assert_eq!(CachePolicy::Full.victim(&[1.0, 2.0]), None);
+
+        let streaming = CachePolicy::Streaming {
+            n_sink: 2,
+            window: 4,
+        };
+        assert_eq!(streaming.victim(&[9.0, 9.0, 5.0, 0.0, 0.0, 0.0]), Some(2));
+
+        // The two newest slots have the least mass but are too recent to judge
+        let h2o = CachePolicy::HeavyHitter {
+            budget: 6,
+            n_recent: 2,
+        };
+        assert_eq!(h2o.victim(&[3.0, 1.0, 0.5, 2.0, 0.0, 0.1]), Some(2));
+        assert_eq!(h2o.victim(&[0.2, 1.0, 0.5, 2.0, 0.0, 0.1]), Some(0));
+    }
+
+    #[test]
+    fn validate_rejects_oversized_policies() {
+        let streaming = |n_sink, window| CachePolicy::Streaming { n_sink, window };
+        assert!(streaming(4, 12).validate(16).is_ok());
+        assert!(streaming(4, 13).validate(16).is_err());
+        assert!(streaming(4, 0).validate(16).is_err());
+        let h2o = |budget, n_recent| CachePolicy::HeavyHitter { budget, n_recent };
+        assert!(h2o(16, 8).validate(16).is_ok());
+        assert!(h2o(17, 8).validate(16).is_err());
+        assert!(h2o(8, 8).validate(16).is_err());
+    }
+}
@@ -0,0 +1,332 @@
+//! Chat prompt templates.
+
+use crate::error::{LlamaError, Result};
+use crate::tokenizer::Tokenize;
+
+/// One turn of a conversation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// `system`, `user` or `assistant`
+    pub role: String,
+    pub content: String,
+}
+
+impl Message {
+    pub fn new(role: &str, content: &str) -> Self {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+}
+
+/// Prompt format of an instruction-tuned model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatTemplate {
+    /// `<s>[INST] <<SYS>>\n{system}\n<</SYS>>\n\n{user} [/INST] {assistant} </s>`
+    Llama2,
+    /// `<|start_header_id|>{role}<|end_header_id|>\n\n{content}<|eot_id|>`
+    Llama3,
+    /// `<|im_start|>{role}\n{content}<|im_end|>\n`
+    ChatMl,
+    /// `<s>[INST] {user} [/INST] {assistant}</s>`
+    Mistral,
+}
+
+/// A rendered piece of a chat prompt.
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Bos,
+    Eos,
+    /// A special token by name
+    Special(&'static str),
+    /// Text encoded as a unit, without parsing special tokens
+    Text(String),
+}
+
+impl ChatTemplate {
+    /// Look up a template by name: `llama2`, `llama3`, `chatml` or `mistral`.
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "llama2" => Ok(ChatTemplate::Llama2),
+            "llama3" => Ok(ChatTemplate::Llama3),
+            "chatml" => Ok(ChatTemplate::ChatMl),
+            "mistral" => Ok(ChatTemplate::Mistral),
+            _ => Err(LlamaError::Config(format!("unknown chat template: {name}"))),
+        }
+    }
+
+    /// Special token that ends an assistant turn, if it is not EOS.
+    pub fn end_of_turn(&self) -> Option<&'static str> {
+        match self {
+            ChatTemplate::Llama3 => Some("<|eot_id|>"),
+            ChatTemplate::ChatMl => Some("<|im_end|>"),
+            ChatTemplate::Llama2 | ChatTemplate::Mistral => None,
+        }
+    }
+
+    /// Encode a conversation. When the last message is not from the
+    /// assistant, the prompt ends where the assistant's reply begins.
+    ///
+    /// Message contents are encoded as plain text, so special token names
+    /// in them never become control tokens. Only the first text segment gets
+    /// the tokenizer's dummy prefix; later ones continue the prompt, so no
+    /// stray space appears after a special token.
+    pub fn encode(&self, tokenizer: &dyn Tokenize, messages: &[Message]) -> Result<Vec<i32>> {
+        let mut tokens = Vec::new();
+        let mut first = true;
+        for part in self.parts(messages)? {
+            match part {
+                Part::Bos => tokens.push(tokenizer.bos_id()),
+                Part::Eos => tokens.push(tokenizer.eos_id()),
+                Part::Special(name) => tokens.push(tokenizer.token_id(name).ok_or_else(|| {
+                    LlamaError::Tokenizer(format!("chat template needs special token {name}"))
+                })?),
+                Part::Text(text) if first => {
+                    tokens.extend(tokenizer.encode(&text, false, false)?);
+                    first = false;
+                }
+                Part::Text(text) => tokens.extend(tokenizer.encode_continuation(&text)?),
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Render a conversation as text, spelling out special tokens.
+    pub fn render(&self, messages: &[Message]) -> Result<String> {
+        Ok(self
+            .parts(messages)?
+            .into_iter()
+            .map(|part| match part {
+                Part::Bos if *self == ChatTemplate::Llama3 => "<|begin_of_text|>".to_string(),
+                Part::Bos => "<s>".to_string(),
+                Part::Eos => "</s>".to_string(),
+                Part::Special(name) => name.to_string(),
+                Part::Text(text) => text,
+            })
+            .collect())
+    }
+
+    fn parts(&self, messages: &[Message]) -> Result<Vec<Part>> {
+        let reply = messages.last().is_none_or(|m| m.role != "assistant");
+        let mut parts = Vec::new();
+        match self {
+            ChatTemplate::Llama2 => {
+                for (user, assistant) in alternating(messages, ("<<SYS>>\n", "\n<</SYS>>\n\n"))? {
+                    parts.push(Part::Bos);
+                    match assistant {
+                        Some(assistant) => {
+                            parts.push(Part::Text(format!(
+                                "[INST] {} [/INST] {} ",
+                                user.trim(),
+                                assistant.trim()
+                            )));
+                            parts.push(Part::Eos);
+                        }
+                        None => parts.push(Part::Text(format!("[INST] {} [/INST]", user.trim()))),
+                    }
+                }
+            }
+            ChatTemplate::Mistral => {
+                parts.push(Part::Bos);
+                for (user, assistant) in alternating(messages, ("", "\n\n"))? {
+                    match assistant {
+                        Some(assistant) => {
+                            parts.push(Part::Text(format!(
+                                "[INST] {} [/INST] {}",
+                                user.trim(),
+                                assistant.trim()
+                            )));
+                            parts.push(Part::Eos);
+                        }
+                        None => parts.push(Part::Text(format!("[INST] {} [/INST]", user.trim()))),
+                    }
+                }
+            }
+            ChatTemplate::Llama3 => {
+                parts.push(Part::Bos);
+                let header = |parts: &mut Vec<Part>, role: &str| {
+                    parts.push(Part::Special("<|start_header_id|>"));
+                    parts.push(Part::Text(role.to_string()));
+                    parts.push(Part::Special("<|end_header_id|>"));
+                    parts.push(Part::Text("\n\n".into()));
+                };
+                for m in messages {
+                    header(&mut parts, &m.role);
+                    parts.push(Part::Text(m.content.trim().to_string()));
+                    parts.push(Part::Special("<|eot_id|>"));
+                }
+                if reply {
+                    header(&mut parts, "assistant");
+                }
+            }
+            ChatTemplate::ChatMl => {
+                for m in messages {
+                    parts.push(Part::Special("<|im_start|>"));
+                    parts.push(Part::Text(format!("{}\n{}", m.role, m.content.trim())));
+                    parts.push(Part::Special("<|im_end|>"));
+                    parts.push(Part::Text("\n".into()));
+                }
+                if reply {
+                    parts.push(Part::Special("<|im_start|>"));
+                    parts.push(Part::Text("assistant\n".into()));
+                }
+            }
+        }
+        Ok(parts)
+    }
+}
+
+/// Pair up user and assistant turns, folding a leading system message,
+/// wrapped in `system_tags`, into the first user turn. A system message
+/// without user turns gets an instruction block of its own.
+fn alternating<'m>(
+    messages: &'m [Message],
+    system_tags: (&str, &str),
+) -> Result<Vec<(String, Option<&'m str>)>> {
+    let (system, rest) = match messages.split_first() {
+        Some((first, rest)) if first.role == "system" => (Some(first.content.trim()), rest),
+        _ => (None, messages),
+    };
+    let (open, close) = system_tags;
+    if rest.is_empty()
+        && let Some(system) = system
+    {
+        return Ok(vec![(format!("{open}{system}{close}"), None)]);
+    }
+    let mut turns = Vec::new();
+    for (i, pair) in rest.chunks(2).enumerate() {
+        let roles: Vec<&str> = pair.iter().map(|m| m.role.as_str()).collect();
+        if roles[0] != "user" || roles.get(1).is_some_and(|&r| r != "assistant") {
+            return Err(LlamaError::Config(format!(
+                "chat roles must alternate user/assistant after an optional system message, got {roles:?}"
+            )));
+        }
+        let mut user = pair[0].content.clone();
+        if i == 0
+            && let Some(system) = system
+        {
+            user = format!("{open}{system}{close}{}", user.trim());
+        }
+        turns.push((user, pair.get(1).map(|m| m.content.as_str())));
+    }
+    Ok(turns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::llama2_tokenizer;
+
+    fn messages(turns: &[(&str, &str)]) -> Vec<Message> {
+        turns
+            .iter()
+            .map(|&(role, content)| Message::new(role, content))
+            .collect()
+    }
+
+    #[test]
+    fn renders_llama2() {
+        let t = ChatTemplate::Llama2;
+        assert_eq!(
+            t.render(&messages(&[("system", "S"), ("user", " Hi ")]))
+                .unwrap(),
+            "<s>[INST] <<SYS>>\nS\n<</SYS>>\n\nHi [/INST]"
+        );
+        assert_eq!(
+            t.render(&messages(&[
+                ("user", "A"),
+                ("assistant", "B"),
+                ("user", "C")
+            ]))
+            .unwrap(),
+            "<s>[INST] A [/INST] B </s><s>[INST] C [/INST]"
+        );
+        assert_eq!(
+            t.render(&messages(&[("system", "S")])).unwrap(),
+            "<s>[INST] <<SYS>>\nS\n<</SYS>> [/INST]"
+        );
+    }
+
+    #[test]
+    fn renders_mistral() {
+        let t = ChatTemplate::Mistral;
+        assert_eq!(
+            t.render(&messages(&[
+                ("system", "S"),
+                ("user", "Hi"),
+                ("assistant", "B"),
+                ("user", "C"),
+            ]))
+            .unwrap(),
+            "<s>[INST] S\n\nHi [/INST] B</s>[INST] C [/INST]"
+        );
+        assert_eq!(
+            t.render(&messages(&[("system", "S")])).unwrap(),
+            "<s>[INST] S [/INST]"
+        );
+    }
+
+    #[test]
+    fn renders_llama3_and_chatml() {
+        let turns = messages(&[("system", "S"), ("user", " Hi\n")]);
+        assert_eq!(
+            ChatTemplate::Llama3.render(&turns).unwrap(),
+            "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\nS<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+        assert_eq!(
+            ChatTemplate::ChatMl.render(&turns).unwrap(),
+            "<|im_start|>system\nS<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+        // A finished assistant turn is not followed by another header
+        assert_eq!(
+            ChatTemplate::ChatMl
+                .render(&messages(&[("user", "Hi"), ("assistant", "B")]))
+                .unwrap(),
+            "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\nB<|im_end|>\n"
+        );
+    }
+
+    #[test]
+    fn rejects_roles_out_of_order() {
+        for turns in [
+            &[("user", "A"), ("user", "B")][..],
+            &[("assistant", "A")],
+            &[("system", "S"), ("system", "T")],
+        ] {
+            assert!(ChatTemplate::Llama2.render(&messages(turns)).is_err());
+            assert!(ChatTemplate::Mistral.render(&messages(turns)).is_err());
+        }
+    }
+
+    #[test]
+    fn encodes_markers_as_tokens_and_content_as_text() {
+        let tok = llama2_tokenizer();
+        let turns = messages(&[("user", "</s>")]);
+        let ids = ChatTemplate::Mistral.encode(&tok, &turns).unwrap();
+        assert_eq!(ids[0], tok.bos_id);
+        assert!(!ids[1..].contains(&tok.eos_id));
+        assert_eq!(tok.decode_all(&ids), "[INST] </s> [/INST]");
+        // The Llama 2 vocabulary has no Llama 3 header tokens
+        assert!(ChatTemplate::Llama3.encode(&tok, &turns).is_err());
+    }
+
+    #[test]
+    fn later_segments_get_no_dummy_prefix() {
+        let tok = llama2_tokenizer();
+        let turns = messages(&[("user", "A"), ("assistant", "B"), ("user", "C")]);
+        let ids = ChatTemplate::Mistral.encode(&tok, &turns).unwrap();
+        let eos = ids.iter().position(|&id| id == tok.eos_id).unwrap();
+        assert_eq!(
+            ids[eos + 1..],
+            tok.encode_continuation("[INST] C [/INST]").unwrap()
+        );
+        assert_eq!(tok.decode_all(&ids), "[INST] A [/INST] B[INST] C [/INST]");
+
+        let ids = ChatTemplate::Llama2.encode(&tok, &turns).unwrap();
+        assert_eq!(tok.decode_all(&ids), "[INST] A [/INST] B [INST] C [/INST]");
+    }
+}
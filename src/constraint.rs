@@ -0,0 +1,18 @@
+//! Constrained decoding by masking logits.
+
+use crate::error::Result;
+
+/// A decoding constraint that masks tokens which cannot continue a valid output.
+///
+/// Call [`mask`](Constraint::mask) on the logits before sampling, then
+/// [`accept`](Constraint::accept) with the sampled token.
+pub trait Constraint {
+    /// Set the logits of every disallowed token to `-inf`.
+    fn mask(&self, logits: &mut [f32]);
+
+    /// Advance past an accepted token, failing if the token was not allowed.
+    fn accept(&mut self, token: i32) -> Result<()>;
+
+    /// Returns true if the output so far is a complete match.
+    fn is_complete(&self) -> bool;
+}
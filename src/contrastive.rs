@@ -0,0 +1,231 @@
+//! Contrastive search decoding.
+
+use crate::config::LlamaConfig;
+use crate::error::{LlamaError, Result};
+use crate::model::forward;
+use crate::ops::softmax;
+use crate::state::LlamaState;
+use crate::weights::LlamaWeights;
+
+/// Contrastive search parameters ([Su et al., 2022](https://arxiv.org/abs/2202.06417)).
+#[derive(Debug, Clone)]
+pub struct ContrastiveConfig {
+    /// Number of most likely candidates evaluated per step
+    pub top_k: usize,
+    /// Weight of the degeneration penalty against model confidence
+    pub alpha: f32,
+    /// Maximum number of generated tokens
+    pub max_tokens: usize,
+    /// Tokens that end generation
+    pub eos_tokens: Vec<i32>,
+}
+
+impl Default for ContrastiveConfig {
+    fn default() -> Self {
+        ContrastiveConfig {
+            top_k: 4,
+            alpha: 0.6,
+            max_tokens: 256,
+            eos_tokens: vec![2],
+        }
+    }
+}
+
+impl ContrastiveConfig {
+    /// Generate after `prompt`, returning the chosen tokens (including a final EOS).
+    ///
+    /// Each step runs every top-k candidate through its own copy of the cache
+    /// and picks the one maximizing `(1 - alpha) * p - alpha * max_cos`, where
+    /// `max_cos` is the highest cosine similarity between the candidate's final
+    /// hidden state and those of all previous positions.
+    pub fn search(
+        &self,
+        prompt: &[i32],
+        config: &LlamaConfig,
+        weights: &LlamaWeights,
+        state: LlamaState,
+    ) -> Result<Vec<i32>> {
+        if self.top_k < 2 {
+            return Err(LlamaError::Config(
+                "contrastive search needs top_k >= 2".into(),
+            ));
+        }
+        if prompt.is_empty() {
+            return Err(LlamaError::Config(
+                "contrastive search needs a prompt".into(),
+            ));
+        }
+        let max_pos = if state.cache_policy.evicts() {
+            usize::MAX
+        } else {
+            config.seq_len as usize
+        };
+
+        let mut main = state;
+        let mut context: Vec<Vec<f32>> = Vec::new();
+        for (pos, &token) in prompt.iter().enumerate() {
+            forward(token, pos as i32, config, &mut main, weights);
+            context.push(unit(&main.x));
+        }
+        let mut branches: Vec<LlamaState> = (0..self.top_k).map(|_| main.clone()).collect();
+
+        let mut out = Vec::new();
+        let end = prompt.len().saturating_add(self.max_tokens).min(max_pos);
+        for pos in prompt.len()..end {
+            let mut probs = main.logits.clone();
+            softmax(&mut probs);
+            let mut ids: Vec<usize> = (0..probs.len()).collect();
+            let k = self.top_k.min(ids.len());
+            ids.select_nth_unstable_by(k - 1, |&a, &b| probs[b].total_cmp(&probs[a]));
+
+            let mut best: Option<(f32, usize, Vec<f32>)> = None;
+            for (b, &id) in ids[..k].iter().enumerate() {
+                let branch = &mut branches[b];
+                branch.copy_cache_from(&main);
+                forward(id as i32, pos as i32, config, branch, weights);
+                let h = unit(&branch.x);
+                let max_cos = context
+                    .iter()
+                    .map(|c| dot(c, &h))
+                    .fold(f32::NEG_INFINITY, f32::max);
+                let score = (1.0 - self.alpha) * probs[id] - self.alpha * max_cos;
+                if best.as_ref().is_none_or(|(s, _, _)| score > *s) {
+                    best = Some((score, b, h));
+                }
+            }
+
+            let Some((_, b, h)) = best else { break };
+            let token = ids[b] as i32;
+            std::mem::swap(&mut main, &mut branches[b]);
+            context.push(h);
+            out.push(token);
+            if self.eos_tokens.contains(&token) {
+                break;
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn unit(x: &[f32]) -> Vec<f32> {
+    let norm = dot(x, x).sqrt().max(f32::MIN_POSITIVE);
+    x.iter().map(|&v| v / norm).collect()
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> LlamaConfig {
+        LlamaConfig {
+            dim: 16,
+            hidden_dim: 32,
+            n_layers: 2,
+            n_heads: 4,
+            n_kv_heads: 2,
+            vocab_size: 32,
+            seq_len: 16,
+        }
+    }
+
+    /// Run `tokens` through a fresh state, returning it and the unit hidden
+    /// state after every position.
+    fn run(
+        config: &LlamaConfig,
+        weights: &LlamaWeights,
+        tokens: &[i32],
+    ) -> (LlamaState, Vec<Vec<f32>>) {
+        let mut state = LlamaState::new(config);
+        let mut hidden = Vec::new();
+        for (pos, &token) in tokens.iter().enumerate() {
+            forward(token, pos as i32, config, &mut state, weights);
+            hidden.push(unit(&state.x));
+        }
+        (state, hidden)
+    }
+
+    fn argmax(x: &[f32]) -> usize {
+        (0..x.len()).max_by(|&a, &b| x[a].total_cmp(&x[b])).unwrap()
+    }
+
+    #[test]
+    fn zero_alpha_is_greedy_decoding() {
+        let config = config();
+        let weights = LlamaWeights::random(&config, 5);
+        let prompt = [1, 2, 3];
+        let search = ContrastiveConfig {
+            alpha: 0.0,
+            max_tokens: 8,
+            eos_tokens: Vec::new(),
+            ..Default::default()
+        };
+        let out = search
+            .search(&prompt, &config, &weights, LlamaState::new(&config))
+            .unwrap();
+
+        let (mut state, _) = run(&config, &weights, &prompt);
+        let mut greedy = Vec::new();
+        for pos in prompt.len()..prompt.len() + 8 {
+            let token = argmax(&state.logits) as i32;
+            greedy.push(token);
+            forward(token, pos as i32, &config, &mut state, &weights);
+        }
+        assert_eq!(out, greedy);
+    }
+
+    #[test]
+    fn degeneration_penalty_matches_reference_scoring() {
+        let config = config();
+        let weights = LlamaWeights::random(&config, 6);
+        let prompt = [4, 4, 7];
+        let search = ContrastiveConfig {
+            top_k: 5,
+            alpha: 0.8,
+            max_tokens: 6,
+            eos_tokens: Vec::new(),
+        };
+        let out = search
+            .search(&prompt, &config, &weights, LlamaState::new(&config))
+            .unwrap();
+
+        // Rescore every step from scratch, without shared caches
+        let mut tokens = prompt.to_vec();
+        let mut greedy_differs = false;
+        for &chosen in &out {
+            let (state, context) = run(&config, &weights, &tokens);
+            let mut probs = state.logits.clone();
+            softmax(&mut probs);
+            let mut ids: Vec<usize> = (0..probs.len()).collect();
+            ids.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+            let scores: Vec<f32> = ids[..search.top_k]
+                .iter()
+                .map(|&id| {
+                    let mut branch = state.clone();
+                    forward(
+                        id as i32,
+                        tokens.len() as i32,
+                        &config,
+                        &mut branch,
+                        &weights,
+                    );
+                    let h = unit(&branch.x);
+                    let max_cos = context
+                        .iter()
+                        .map(|c| dot(c, &h))
+                        .fold(f32::NEG_INFINITY, f32::max);
+                    (1.0 - search.alpha) * probs[id] - search.alpha * max_cos
+                })
+                .collect();
+            let expected = ids[argmax(&scores)] as i32;
+            assert_eq!(chosen, expected, "after {tokens:?}");
+            greedy_differs |= expected != ids[0] as i32;
+            tokens.push(chosen);
+        }
+        // The penalty must have overridden the most likely token at least once
+        assert!(greedy_differs, "{out:?}");
+    }
+}
@@ -12,6 +12,18 @@ pub enum LlamaError {
 
     #[error("Tokenizer error: {0}")]
     Tokenizer(String),
+
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    #[error("Grammar error: {0}")]
+    Grammar(String),
+
+    #[error("JSON error: {0}")]
+    Json(String),
+
+    #[error("Sampling error: {0}")]
+    Sampling(String),
 }
 
 pub type Result<T> = std::result::Result<T, LlamaError>;
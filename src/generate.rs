@@ -0,0 +1,220 @@
+//! Token-by-token generation with optional constraints and guidance.
+
+use crate::config::LlamaConfig;
+use crate::constraint::Constraint;
+use crate::error::{LlamaError, Result};
+use crate::model::forward;
+use crate::ops::log_sum_exp;
+use crate::sample::{Sampler, TokenLogprob, logprobs};
+use crate::state::LlamaState;
+use crate::weights::LlamaWeights;
+
+/// Classifier-free guidance: a second state that runs on a negative (or
+/// unconditional) prompt in lockstep with the main one.
+#[derive(Debug, Clone)]
+pub struct Guidance {
+    pub state: LlamaState,
+    /// 1.0 leaves the logits unchanged; larger values push away from the negative prompt
+    pub scale: f32,
+    pos: usize,
+}
+
+impl Guidance {
+    pub fn new(state: LlamaState, scale: f32) -> Self {
+        Guidance {
+            state,
+            scale,
+            pos: 0,
+        }
+    }
+}
+
+/// Mix conditional and unconditional logits in log-probability space:
+/// `uncond + scale * (cond - uncond)`.
+pub fn apply_guidance(logits: &mut [f32], uncond: &[f32], scale: f32) {
+    let cond_norm = log_sum_exp(logits);
+    let uncond_norm = log_sum_exp(uncond);
+    for (l, &u) in logits.iter_mut().zip(uncond) {
+        let (c, u) = (*l - cond_norm, u - uncond_norm);
+        *l = u + scale * (c - u);
+    }
+}
+
+/// Drives the forward pass and sampler one token at a time.
+///
+/// A sampled token is only run through the model when the next one is
+/// requested, so stopping on EOS costs no extra forward pass.
+pub struct Generator<'a> {
+    pub config: &'a LlamaConfig,
+    pub weights: &'a LlamaWeights,
+    pub state: LlamaState,
+    pub sampler: Sampler,
+    pub constraint: Option<Box<dyn Constraint>>,
+    pub guidance: Option<Guidance>,
+    pos: usize,
+    pending: Option<i32>,
+}
+
+impl<'a> Generator<'a> {
+    pub fn new(
+        config: &'a LlamaConfig,
+        weights: &'a LlamaWeights,
+        state: LlamaState,
+        sampler: Sampler,
+    ) -> Self {
+        Generator {
+            config,
+            weights,
+            state,
+            sampler,
+            constraint: None,
+            guidance: None,
+            pos: 0,
+            pending: None,
+        }
+    }
+
+    /// Enable classifier-free guidance, running `negative_prompt` through `state`.
+    pub fn set_guidance(
+        &mut self,
+        negative_prompt: &[i32],
+        scale: f32,
+        state: LlamaState,
+    ) -> Result<()> {
+        let mut guidance = Guidance::new(state, scale);
+        for &token in negative_prompt {
+            guidance.pos = step(
+                self.config,
+                self.weights,
+                &mut guidance.state,
+                guidance.pos,
+                token,
+            )?;
+        }
+        self.guidance = Some(guidance);
+        Ok(())
+    }
+
+    /// Number of tokens run through the model so far.
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// Feed tokens without sampling, e.g. the prompt.
+    ///
+    /// Under guidance the negative prompt stands in for these tokens, so they
+    /// only reach the main state; sampled tokens are fed to both.
+    pub fn prompt(&mut self, tokens: &[i32]) -> Result<()> {
+        if let Some(token) = self.pending.take() {
+            self.feed(token)?;
+        }
+        for &token in tokens {
+            self.sampler.accept(token);
+            self.pos = step(self.config, self.weights, &mut self.state, self.pos, token)?;
+        }
+        Ok(())
+    }
+
+    /// Sample the next token from the current logits.
+    pub fn next_token(&mut self) -> Result<i32> {
+        self.advance()?;
+        self.sample()
+    }
+
+    /// Sample the next token and report its log-probability along with the
+    /// `n_top` most likely alternatives, taken from the raw model logits
+    /// before guidance or any constraint is applied.
+    pub fn next_token_logprobs(&mut self, n_top: usize) -> Result<TokenLogprob> {
+        self.advance()?;
+        let logits = self.state.logits.clone();
+        let token = self.sample()?;
+        Ok(logprobs(&logits, token, n_top))
+    }
+
+    /// Run the previously sampled token through the model.
+    fn advance(&mut self) -> Result<()> {
+        if let Some(token) = self.pending.take() {
+            self.feed(token)?;
+        }
+        if self.pos == 0 {
+            return Err(LlamaError::Config("generation needs a prompt".into()));
+        }
+        Ok(())
+    }
+
+    fn sample(&mut self) -> Result<i32> {
+        let mut logits = self.state.logits.clone();
+        if let Some(g) = &self.guidance {
+            apply_guidance(&mut logits, &g.state.logits, g.scale);
+        }
+        if let Some(c) = self.constraint.as_mut() {
+            c.mask(&mut logits);
+        }
+        let token = self.sampler.sample(&logits)?;
+        if let Some(c) = self.constraint.as_mut() {
+            c.accept(token)?;
+        }
+        self.pending = Some(token);
+        Ok(token)
+    }
+
+    /// Run `token` through the main state and, with guidance, the negative one.
+    fn feed(&mut self, token: i32) -> Result<()> {
+        self.pos = step(self.config, self.weights, &mut self.state, self.pos, token)?;
+        if let Some(g) = self.guidance.as_mut() {
+            g.pos = step(self.config, self.weights, &mut g.state, g.pos, token)?;
+        }
+        Ok(())
+    }
+}
+
+/// Forward one token at `pos`, returning the next position.
+fn step(
+    config: &LlamaConfig,
+    weights: &LlamaWeights,
+    state: &mut LlamaState,
+    pos: usize,
+    token: i32,
+) -> Result<usize> {
+    if !state.cache_policy.evicts() && pos >= config.seq_len as usize {
+        return Err(LlamaError::Config(format!(
+            "context length of {} tokens exceeded",
+            config.seq_len
+        )));
+    }
+    forward(token, pos as i32, config, state, weights);
+    Ok(pos + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::log_softmax;
+
+    #[test]
+    fn guidance_scale_selects_conditional_or_unconditional() {
+        let cond = [2.0, -1.0, 0.5, 3.0];
+        let uncond = [0.0, 1.0, -2.0, 4.0];
+        let normalised = |l: &[f32]| {
+            let mut l = l.to_vec();
+            log_softmax(&mut l);
+            l
+        };
+        let close = |a: &[f32], b: &[f32]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-5);
+
+        let mut logits = cond;
+        apply_guidance(&mut logits, &uncond, 1.0);
+        assert!(close(&logits, &normalised(&cond)), "{logits:?}");
+
+        let mut logits = cond;
+        apply_guidance(&mut logits, &uncond, 0.0);
+        assert!(close(&logits, &normalised(&uncond)), "{logits:?}");
+
+        // Past 1 the mix extrapolates away from the unconditional logits
+        let mut logits = cond;
+        apply_guidance(&mut logits, &uncond, 2.0);
+        let (c, u) = (normalised(&cond), normalised(&uncond));
+        let expected: Vec<f32> = c.iter().zip(&u).map(|(c, u)| 2.0 * c - u).collect();
+        assert!(close(&logits, &expected), "{logits:?}");
+    }
+}
@@ -0,0 +1,914 @@
+//! GBNF-style grammars for constrained decoding.
+//!
+//! The syntax follows llama.cpp's GBNF: rules of the form `name ::= body`,
+//! alternatives with `|`, string literals, character classes (`[a-z]`, `[^"]`),
+//! `.` for any character, groups, and the repetition operators `*`, `+`, `?`
+//! and `{m,n}`. Decoding starts from the `root` rule.
+//!
+//! Matching uses a set of pushdown stacks, as in llama.cpp: each stack holds
+//! the positions still to be matched, with a character element on top.
+
+use crate::constraint::Constraint;
+use crate::error::{LlamaError, Result};
+use crate::tokenizer::Tokenize;
+use std::collections::{HashMap, HashSet};
+
+/// A grammar element.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Element {
+    /// One character inside (or, if negated, outside) the inclusive ranges
+    Char {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    /// A reference to another rule
+    Rule(usize),
+}
+
+impl Element {
+    /// Returns true if this element is a character class matching `c`.
+    #[inline]
+    pub fn matches(&self, c: char) -> bool {
+        match self {
+            Element::Char { ranges, negated } => {
+                ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+            }
+            Element::Rule(_) => false,
+        }
+    }
+
+    /// Returns true if this element could match some non-ASCII character.
+    fn matches_non_ascii(&self) -> bool {
+        match self {
+            Element::Char { ranges, negated } => {
+                *negated || ranges.iter().any(|&(_, hi)| hi >= '\u{80}')
+            }
+            Element::Rule(_) => false,
+        }
+    }
+}
+
+/// A parsed grammar: each rule is a list of alternatives, each a sequence of elements.
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    pub rules: Vec<Vec<Vec<Element>>>,
+    pub names: Vec<String>,
+    pub root: usize,
+}
+
+/// A position inside a rule alternative: the next element to match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Pos {
+    pub rule: usize,
+    pub alt: usize,
+    pub idx: usize,
+}
+
+/// A parse stack; the last position is the next element to match.
+pub type Stack = Vec<Pos>;
+
+impl Grammar {
+    /// Parse a grammar in GBNF syntax.
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: src.chars().collect(),
+            pos: 0,
+            ids: HashMap::new(),
+            names: Vec::new(),
+            rules: Vec::new(),
+        };
+        parser.parse()?;
+
+        let Parser {
+            names, rules, ids, ..
+        } = parser;
+        let mut defined = Vec::with_capacity(rules.len());
+        for (name, rule) in names.iter().zip(rules) {
+            match rule {
+                Some(rule) => defined.push(rule),
+                None => return Err(grammar_err(format!("undefined rule: {name}"))),
+            }
+        }
+        let root = *ids
+            .get("root")
+            .ok_or_else(|| grammar_err("grammar has no root rule".into()))?;
+
+        let grammar = Grammar {
+            rules: defined,
+            names,
+            root,
+        };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+
+    /// Returns the stacks before any character has been matched.
+    pub fn initial_stacks(&self) -> Vec<Stack> {
+        let mut out = Vec::new();
+        for alt in 0..self.rules[self.root].len() {
+            let pos = Pos {
+                rule: self.root,
+                alt,
+                idx: 0,
+            };
+            self.expand(vec![pos], &mut out);
+        }
+        dedup(out)
+    }
+
+    /// Advance every stack whose next element matches `c`.
+    pub fn advance(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut out = Vec::new();
+        for stack in stacks {
+            let Some(&top) = stack.last() else { continue };
+            if self.element(top).matches(c) {
+                let mut next = stack.clone();
+                next.pop();
+                self.push_next(&mut next, top);
+                self.expand(next, &mut out);
+            }
+        }
+        dedup(out)
+    }
+
+    #[inline]
+    fn element(&self, pos: Pos) -> &Element {
+        &self.rules[pos.rule][pos.alt][pos.idx]
+    }
+
+    /// Push the position after `pos`, unless it ends its alternative.
+    #[inline]
+    fn push_next(&self, stack: &mut Stack, pos: Pos) {
+        if pos.idx + 1 < self.rules[pos.rule][pos.alt].len() {
+            stack.push(Pos {
+                idx: pos.idx + 1,
+                ..pos
+            });
+        }
+    }
+
+    /// Expand rule references until every stack has a character element on
+    /// top, or is empty (the grammar is complete).
+    fn expand(&self, mut stack: Stack, out: &mut Vec<Stack>) {
+        loop {
+            let Some(&top) = stack.last() else {
+                out.push(stack);
+                return;
+            };
+            if top.idx == self.rules[top.rule][top.alt].len() {
+                // Empty alternative
+                stack.pop();
+                continue;
+            }
+            match *self.element(top) {
+                Element::Char { .. } => {
+                    out.push(stack);
+                    return;
+                }
+                Element::Rule(rule) => {
+                    stack.pop();
+                    self.push_next(&mut stack, top);
+                    for alt in 0..self.rules[rule].len() {
+                        let mut next = stack.clone();
+                        next.push(Pos { rule, alt, idx: 0 });
+                        self.expand(next, out);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Reject left-recursive rules, which the stack expansion cannot handle.
+    fn check_left_recursion(&self) -> Result<()> {
+        // Nullable rules, by fixpoint
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (r, alts) in self.rules.iter().enumerate() {
+                if nullable[r] {
+                    continue;
+                }
+                let is_nullable = alts.iter().any(|alt| {
+                    alt.iter()
+                        .all(|e| matches!(*e, Element::Rule(x) if nullable[x]))
+                });
+                if is_nullable {
+                    nullable[r] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        // Rules reachable from the start of each rule without consuming input
+        let leading: Vec<Vec<usize>> = self
+            .rules
+            .iter()
+            .map(|alts| {
+                let mut refs = Vec::new();
+                for alt in alts {
+                    for e in alt {
+                        match *e {
+                            Element::Rule(x) => {
+                                refs.push(x);
+                                if !nullable[x] {
+                                    break;
+                                }
+                            }
+                            Element::Char { .. } => break,
+                        }
+                    }
+                }
+                refs
+            })
+            .collect();
+
+        for start in 0..self.rules.len() {
+            let mut seen = HashSet::new();
+            let mut todo = leading[start].clone();
+            while let Some(r) = todo.pop() {
+                if r == start {
+                    return Err(grammar_err(format!(
+                        "left recursion in rule: {}",
+                        self.names[start]
+                    )));
+                }
+                if seen.insert(r) {
+                    todo.extend(&leading[r]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn dedup(mut stacks: Vec<Stack>) -> Vec<Stack> {
+    stacks.sort();
+    stacks.dedup();
+    stacks
+}
+
+fn grammar_err(msg: String) -> LlamaError {
+    LlamaError::Grammar(msg)
+}
+
+/// Recursive-descent parser for GBNF.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    ids: HashMap<String, usize>,
+    names: Vec<String>,
+    rules: Vec<Option<Vec<Vec<Element>>>>,
+}
+
+impl Parser {
+    fn parse(&mut self) -> Result<()> {
+        loop {
+            self.skip_space(true);
+            if self.peek().is_none() {
+                return Ok(());
+            }
+            let name = self.parse_name()?;
+            self.skip_space(false);
+            self.expect("::=")?;
+            self.skip_space(true);
+            let alts = self.parse_alternates(&name, false)?;
+
+            let id = self.symbol(&name);
+            if self.rules[id].is_some() {
+                return Err(grammar_err(format!("rule defined twice: {name}")));
+            }
+            self.rules[id] = Some(alts);
+
+            self.skip_space(false);
+            match self.peek() {
+                None | Some('\n' | '\r') => {}
+                Some(c) => return Err(self.error(&format!("unexpected '{c}'"))),
+            }
+        }
+    }
+
+    fn parse_alternates(&mut self, rule: &str, nested: bool) -> Result<Vec<Vec<Element>>> {
+        let mut alts = vec![self.parse_sequence(rule, nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alts.push(self.parse_sequence(rule, nested)?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_sequence(&mut self, rule: &str, nested: bool) -> Result<Vec<Element>> {
+        let mut seq = Vec::new();
+        // Start of the last item, for repetition operators
+        let mut last: Option<usize> = None;
+
+        while let Some(c) = self.peek() {
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    last = Some(seq.len());
+                    loop {
+                        match self.peek() {
+                            None => return Err(self.error("unterminated string")),
+                            Some('"') => break,
+                            Some(_) => {
+                                let c = self.parse_char()?;
+                                seq.push(Element::Char {
+                                    ranges: vec![(c, c)],
+                                    negated: false,
+                                });
+                            }
+                        }
+                    }
+                    self.pos += 1;
+                }
+                '[' => {
+                    self.pos += 1;
+                    last = Some(seq.len());
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = Vec::new();
+                    loop {
+                        match self.peek() {
+                            None => return Err(self.error("unterminated character class")),
+                            Some(']') => break,
+                            Some(_) => {
+                                let lo = self.parse_char()?;
+                                let hi = if self.peek() == Some('-')
+                                    && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']')
+                                {
+                                    self.pos += 1;
+                                    self.parse_char()?
+                                } else {
+                                    lo
+                                };
+                                ranges.push((lo, hi));
+                            }
+                        }
+                    }
+                    self.pos += 1;
+                    seq.push(Element::Char { ranges, negated });
+                }
+                '.' => {
+                    self.pos += 1;
+                    last = Some(seq.len());
+                    seq.push(Element::Char {
+                        ranges: Vec::new(),
+                        negated: true,
+                    });
+                }
+                '(' => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alts = self.parse_alternates(rule, true)?;
+                    self.skip_space(true);
+                    self.expect(")")?;
+                    last = Some(seq.len());
+                    let id = self.generated(rule, alts);
+                    seq.push(Element::Rule(id));
+                }
+                '*' | '+' | '?' | '{' => {
+                    let Some(start) = last.take() else {
+                        return Err(self.error("repetition without a preceding item"));
+                    };
+                    let (min, max) = self.parse_repetition()?;
+                    let item = seq.split_off(start);
+                    let repeated = self.repeat(rule, item, min, max);
+                    seq.extend(repeated);
+                }
+                c if is_name_char(c) => {
+                    let name = self.parse_name()?;
+                    last = Some(seq.len());
+                    seq.push(Element::Rule(self.symbol(&name)));
+                }
+                _ => break,
+            }
+            self.skip_space(nested);
+        }
+        Ok(seq)
+    }
+
+    /// Parse `*`, `+`, `?`, `{m}`, `{m,}` or `{m,n}` into (min, max).
+    fn parse_repetition(&mut self) -> Result<(usize, Option<usize>)> {
+        let c = self.peek().unwrap_or_default();
+        self.pos += 1;
+        match c {
+            '*' => Ok((0, None)),
+            '+' => Ok((1, None)),
+            '?' => Ok((0, Some(1))),
+            _ => {
+                self.skip_space(false);
+                let min = self.parse_int()?;
+                self.skip_space(false);
+                let max = if self.peek() == Some(',') {
+                    self.pos += 1;
+                    self.skip_space(false);
+                    if self.peek() == Some('}') {
+                        None
+                    } else {
+                        Some(self.parse_int()?)
+                    }
+                } else {
+                    Some(min)
+                };
+                self.skip_space(false);
+                self.expect("}")?;
+                if max.is_some_and(|max| max < min) {
+                    return Err(self.error("repetition maximum below minimum"));
+                }
+                Ok((min, max))
+            }
+        }
+    }
+
+    /// Desugar `item{min,max}` into plain rules.
+    fn repeat(
+        &mut self,
+        rule: &str,
+        item: Vec<Element>,
+        min: usize,
+        max: Option<usize>,
+    ) -> Vec<Element> {
+        let mut out = Vec::new();
+        for _ in 0..min {
+            out.extend(item.iter().cloned());
+        }
+        match max {
+            // R ::= item R | ε
+            None => {
+                let id = self.generated(rule, Vec::new());
+                let mut alt = item;
+                alt.push(Element::Rule(id));
+                self.rules[id] = Some(vec![alt, Vec::new()]);
+                out.push(Element::Rule(id));
+            }
+            // R_k ::= item R_(k-1) | ε, nested (max - min) deep
+            Some(max) => {
+                let mut tail = None;
+                for _ in min..max {
+                    let mut alt = item.clone();
+                    alt.extend(tail.map(Element::Rule));
+                    tail = Some(self.generated(rule, vec![alt, Vec::new()]));
+                }
+                out.extend(tail.map(Element::Rule));
+            }
+        }
+        out
+    }
+
+    /// Returns the id of a named rule, reserving it if not yet defined.
+    fn symbol(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = self.names.len();
+        self.ids.insert(name.to_string(), id);
+        self.names.push(name.to_string());
+        self.rules.push(None);
+        id
+    }
+
+    /// Add an anonymous rule derived from `rule`.
+    fn generated(&mut self, rule: &str, alts: Vec<Vec<Element>>) -> usize {
+        let id = self.names.len();
+        self.names.push(format!("{rule}_{id}"));
+        self.rules.push(Some(alts));
+        id
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_name_char) {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return Err(self.error("expected a rule name"));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn parse_int(&mut self) -> Result<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        digits
+            .parse()
+            .map_err(|_| self.error("expected an integer"))
+    }
+
+    /// Parse one possibly escaped character of a literal or class.
+    fn parse_char(&mut self) -> Result<char> {
+        let c = self.peek().ok_or_else(|| self.error("unexpected end"))?;
+        self.pos += 1;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let esc = self.peek().ok_or_else(|| self.error("unexpected end"))?;
+        self.pos += 1;
+        let digits = match esc {
+            'n' => return Ok('\n'),
+            'r' => return Ok('\r'),
+            't' => return Ok('\t'),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            other => return Ok(other),
+        };
+        let end = (self.pos + digits).min(self.chars.len());
+        let hex: String = self.chars[self.pos..end].iter().collect();
+        self.pos = end;
+        u32::from_str_radix(&hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error("invalid escape"))
+    }
+
+    /// Skip blanks and comments, and newlines if `newlines` is set.
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '\n' | '\r' if newlines => self.pos += 1,
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<()> {
+        for expected in s.chars() {
+            if self.peek() != Some(expected) {
+                return Err(self.error(&format!("expected '{s}'")));
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn error(&self, msg: &str) -> LlamaError {
+        let line = self.chars[..self.pos.min(self.chars.len())]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count();
+        grammar_err(format!("{msg} at line {}", line + 1))
+    }
+}
+
+#[inline]
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+/// Split bytes into complete characters and the length of a trailing
+/// incomplete UTF-8 sequence. Returns `None` for invalid UTF-8.
+fn decode_utf8(bytes: &[u8]) -> Option<(&str, usize)> {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => Some((s, 0)),
+        Err(e) if e.error_len().is_none() => {
+            let valid = e.valid_up_to();
+            let s = std::str::from_utf8(&bytes[..valid]).ok()?;
+            Some((s, bytes.len() - valid))
+        }
+        Err(_) => None,
+    }
+}
+
+/// Grammar matcher that masks vocabulary entries which cannot continue a parse.
+#[derive(Debug, Clone)]
+pub struct GrammarConstraint {
+    pub grammar: Grammar,
+    stacks: Vec<Stack>,
+    /// Bytes of an incomplete UTF-8 character left by the last token
+    partial: Vec<u8>,
+    /// Text bytes of every vocabulary entry (empty for control tokens)
+    pieces: Vec<Vec<u8>>,
+    /// Byte trie over `pieces`, so `mask` advances shared prefixes once
+    trie: Vec<TrieNode>,
+    eos_tokens: Vec<i32>,
+}
+
+/// A node of the vocabulary byte trie.
+#[derive(Debug, Clone, Default)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    /// Tokens whose piece ends at this node
+    tokens: Vec<i32>,
+}
+
+impl GrammarConstraint {
+    /// Create a matcher for `grammar` over the tokenizer's vocabulary.
+    pub fn new(grammar: Grammar, tokenizer: &dyn Tokenize, eos_tokens: &[i32]) -> Self {
+        let pieces: Vec<Vec<u8>> = (0..tokenizer.vocab_size() as i32)
+            .map(|id| tokenizer.piece_bytes(id).unwrap_or_default())
+            .collect();
+        let mut trie = vec![TrieNode::default()];
+        for (id, piece) in pieces.iter().enumerate() {
+            if piece.is_empty() || eos_tokens.contains(&(id as i32)) {
+                continue;
+            }
+            let mut node = 0;
+            for &b in piece {
+                node = match trie[node].children.iter().find(|&&(c, _)| c == b) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        trie.push(TrieNode::default());
+                        let child = trie.len() - 1;
+                        trie[node].children.push((b, child));
+                        child
+                    }
+                };
+            }
+            trie[node].tokens.push(id as i32);
+        }
+        let stacks = grammar.initial_stacks();
+        GrammarConstraint {
+            grammar,
+            stacks,
+            partial: Vec::new(),
+            pieces,
+            trie,
+            eos_tokens: eos_tokens.to_vec(),
+        }
+    }
+
+    /// Feed bytes to the matcher state, returning the new stacks and the
+    /// trailing incomplete bytes, or `None` if the bytes are rejected.
+    fn feed(&self, bytes: &[u8]) -> Option<(Vec<Stack>, usize)> {
+        let joined;
+        let bytes = if self.partial.is_empty() {
+            bytes
+        } else {
+            joined = [self.partial.as_slice(), bytes].concat();
+            &joined
+        };
+        let (text, incomplete) = decode_utf8(bytes)?;
+
+        let mut chars = text.chars();
+        let mut stacks = match chars.next() {
+            // Cheap check of the first character before allocating
+            Some(c) => {
+                if !self.stacks.iter().any(|s| self.top_matches(s, c)) {
+                    return None;
+                }
+                self.grammar.advance(&self.stacks, c)
+            }
+            None => self.stacks.clone(),
+        };
+        for c in chars {
+            stacks = self.grammar.advance(&stacks, c);
+            if stacks.is_empty() {
+                return None;
+            }
+        }
+
+        // An incomplete character must be able to continue somewhere
+        if incomplete > 0 && !stacks.iter().any(|s| self.top_matches_non_ascii(s)) {
+            return None;
+        }
+        Some((stacks, incomplete))
+    }
+
+    /// Mark every token in the subtree of `node` that the grammar accepts,
+    /// given the stacks and incomplete UTF-8 bytes reached at that node.
+    fn walk(&self, node: usize, stacks: &[Stack], pending: &[u8], allowed: &mut [bool]) {
+        let node = &self.trie[node];
+        // Same rule as `feed`: an incomplete character must be able to continue
+        if !node.tokens.is_empty()
+            && (pending.is_empty() || stacks.iter().any(|s| self.top_matches_non_ascii(s)))
+        {
+            for &id in &node.tokens {
+                if let Some(a) = allowed.get_mut(id as usize) {
+                    *a = true;
+                }
+            }
+        }
+
+        let mut buf = [0u8; 4];
+        buf[..pending.len()].copy_from_slice(pending);
+        for &(b, child) in &node.children {
+            buf[pending.len()] = b;
+            let bytes = &buf[..=pending.len()];
+            match std::str::from_utf8(bytes) {
+                Ok(text) => {
+                    let c = text.chars().next().unwrap();
+                    // Cheap check before allocating, as in `feed`
+                    if !stacks.iter().any(|s| self.top_matches(s, c)) {
+                        continue;
+                    }
+                    let next = self.grammar.advance(stacks, c);
+                    if !next.is_empty() {
+                        self.walk(child, &next, &[], allowed);
+                    }
+                }
+                Err(e) if e.error_len().is_none() => self.walk(child, stacks, bytes, allowed),
+                Err(_) => {}
+            }
+        }
+    }
+
+    #[inline]
+    fn top_matches_non_ascii(&self, stack: &Stack) -> bool {
+        stack
+            .last()
+            .is_some_and(|&p| self.grammar.element(p).matches_non_ascii())
+    }
+
+    #[inline]
+    fn top_matches(&self, stack: &Stack, c: char) -> bool {
+        stack
+            .last()
+            .is_some_and(|&p| self.grammar.element(p).matches(c))
+    }
+}
+
+impl Constraint for GrammarConstraint {
+    fn mask(&self, logits: &mut [f32]) {
+        let mut allowed = vec![false; logits.len()];
+        self.walk(0, &self.stacks, &self.partial, &mut allowed);
+        if self.is_complete() {
+            for &id in &self.eos_tokens {
+                if let Some(a) = allowed.get_mut(id as usize) {
+                    *a = true;
+                }
+            }
+        }
+        for (logit, ok) in logits.iter_mut().zip(allowed) {
+            if !ok {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    fn accept(&mut self, token: i32) -> Result<()> {
+        if self.eos_tokens.contains(&token) {
+            if self.is_complete() {
+                self.stacks.clear();
+                return Ok(());
+            }
+            return Err(grammar_err(
+                "end of generation before the grammar is complete".into(),
+            ));
+        }
+        let bytes = self
+            .pieces
+            .get(token as usize)
+            .filter(|b| !b.is_empty())
+            .ok_or_else(|| grammar_err(format!("token {token} has no text")))?;
+        let (stacks, incomplete) = self
+            .feed(bytes)
+            .ok_or_else(|| grammar_err(format!("token {token} rejected by grammar")))?;
+
+        let mut all = std::mem::take(&mut self.partial);
+        all.extend_from_slice(bytes);
+        self.partial = all.split_off(all.len() - incomplete);
+        self.stacks = stacks;
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.partial.is_empty() && self.stacks.iter().any(|s| s.is_empty())
+    }
+}
+
+#[cfg(test)]
+impl Grammar {
+    /// Returns true if the grammar matches all of `text`.
+    pub(crate) fn matches(&self, text: &str) -> bool {
+        let mut stacks = self.initial_stacks();
+        for c in text.chars() {
+            stacks = self.advance(&stacks, c);
+        }
+        stacks.iter().any(|s| s.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::llama2_tokenizer;
+
+    #[test]
+    fn matches_sequences_classes_and_repetition() {
+        let g = Grammar::parse(
+            r#"root ::= "a" digit+ ("x" | "y")? [^z]{0,2}
+digit ::= [0-9]"#,
+        )
+        .unwrap();
+        for ok in ["a1", "a12x", "a9yqq", "a0b"] {
+            assert!(g.matches(ok), "{ok}");
+        }
+        for bad in ["a", "ax", "a1z", "a1xqqq", "b1"] {
+            assert!(!g.matches(bad), "{bad}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_grammars() {
+        assert!(Grammar::parse("root ::= missing").is_err());
+        assert!(Grammar::parse("root ::= root \"a\" | \"b\"").is_err());
+        assert!(Grammar::parse("root ::= \"a").is_err());
+    }
+
+    #[test]
+    fn constraint_masks_tokens_and_eos() {
+        let tok = llama2_tokenizer();
+        let eos = tok.eos_id;
+        let mut c = GrammarConstraint::new(
+            Grammar::parse(r#"root ::= "yes" | "no""#).unwrap(),
+            &tok,
+            &[eos],
+        );
+        let mut logits = vec![0.0; 32000];
+        c.mask(&mut logits);
+        for (id, &l) in logits.iter().enumerate() {
+            if l.is_finite() {
+                let piece = tok.piece_bytes(id as i32).unwrap();
+                assert!(b"yes".starts_with(&piece) || b"no".starts_with(&piece));
+            }
+        }
+        assert!(logits[tok.token_id("yes").unwrap() as usize].is_finite());
+        assert_eq!(logits[eos as usize], f32::NEG_INFINITY);
+
+        c.accept(tok.token_id("y").unwrap()).unwrap();
+        assert!(!c.is_complete());
+        assert!(c.accept(tok.token_id("no").unwrap()).is_err());
+        c.accept(tok.token_id("es").unwrap()).unwrap();
+        assert!(c.is_complete());
+        let mut logits = vec![0.0; 32000];
+        c.mask(&mut logits);
+        assert!(logits[eos as usize].is_finite());
+    }
+
+    #[test]
+    fn constraint_joins_utf8_split_across_byte_tokens() {
+        let tok = llama2_tokenizer();
+        let mut c = GrammarConstraint::new(Grammar::parse(r#"root ::= "é""#).unwrap(), &tok, &[2]);
+        let [b0, b1] = "é".as_bytes() else { panic!() };
+        c.accept(tok.byte_tokens[*b0 as usize]).unwrap();
+        assert!(!c.is_complete());
+        assert!(c.accept(tok.byte_tokens[b'a' as usize]).is_err());
+        c.accept(tok.byte_tokens[*b1 as usize]).unwrap();
+        assert!(c.is_complete());
+    }
+
+    #[test]
+    fn trie_mask_matches_feeding_each_token() {
+        let tok = llama2_tokenizer();
+        let eos = tok.eos_id;
+        let grammar = Grammar::parse(
+            r#"root ::= "{" ws pair ("," ws pair)* ws "}"
+pair ::= "\"" [a-zé日]+ "\":" ws [0-9]+
+ws ::= [ \n]*"#,
+        )
+        .unwrap();
+        let mut c = GrammarConstraint::new(grammar, &tok, &[eos]);
+        let check = |c: &GrammarConstraint| {
+            let mut logits = vec![0.0; tok.vocab_size()];
+            c.mask(&mut logits);
+            for (id, &l) in logits.iter().enumerate() {
+                let expected = if id as i32 == eos {
+                    c.is_complete()
+                } else {
+                    !c.pieces[id].is_empty() && c.feed(&c.pieces[id]).is_some()
+                };
+                assert_eq!(l.is_finite(), expected, "token {id} {:?}", tok.vocab[id]);
+            }
+        };
+        check(&c);
+        let [b0, b1, b2] = "日".as_bytes() else {
+            panic!()
+        };
+        let steps = [
+            tok.token_id("{").unwrap(),
+            tok.token_id("\"").unwrap(),
+            tok.token_id("ab").unwrap(),
+            tok.token_id("é").unwrap(),
+            tok.byte_tokens[*b0 as usize],
+            tok.byte_tokens[*b1 as usize],
+            tok.byte_tokens[*b2 as usize],
+            tok.token_id("\":").unwrap(),
+            tok.token_id("4").unwrap(),
+            tok.token_id("}").unwrap(),
+        ];
+        for id in steps {
+            c.accept(id).unwrap();
+            check(&c);
+        }
+        assert!(c.is_complete());
+    }
+}
@@ -0,0 +1,169 @@
+//! Token healing at the prompt boundary.
+//!
+//! A prompt that ends mid-word, such as `"https:"`, is tokenized as if the
+//! word ended there, so the model never sees the token it would normally use
+//! (`"://"`) and continues from an unusual boundary. Healing removes the last
+//! prompt tokens and lets the model regenerate their text: until the removed
+//! bytes have been reproduced, only tokens consistent with them are allowed.
+
+use crate::constraint::Constraint;
+use crate::error::{LlamaError, Result};
+use crate::tokenizer::Tokenize;
+
+/// Constrains the first sampled tokens to spell out the text removed from
+/// the end of the prompt, after which any token is allowed.
+#[derive(Debug, Clone)]
+pub struct TokenHealing {
+    /// Removed bytes not yet reproduced
+    remaining: Vec<u8>,
+    pieces: Vec<Vec<u8>>,
+}
+
+impl TokenHealing {
+    /// Back off up to `max_tokens` tokens from the end of `tokens`, removing
+    /// them, and return a constraint that regenerates their text.
+    ///
+    /// Backing off stops at control tokens such as BOS, and always leaves at
+    /// least one token to run the model on.
+    pub fn new(tokenizer: &dyn Tokenize, tokens: &mut Vec<i32>, max_tokens: usize) -> Self {
+        let pieces: Vec<Vec<u8>> = (0..tokenizer.vocab_size() as i32)
+            .map(|id| tokenizer.piece_bytes(id).unwrap_or_default())
+            .collect();
+        let mut remaining = Vec::new();
+        for _ in 0..max_tokens {
+            let bytes = match tokens.last() {
+                Some(&t) if tokens.len() > 1 => pieces.get(t as usize).map_or(&[][..], |p| p),
+                _ => break,
+            };
+            if bytes.is_empty() {
+                break;
+            }
+            remaining.splice(0..0, bytes.iter().copied());
+            tokens.pop();
+        }
+        TokenHealing { remaining, pieces }
+    }
+
+    /// Prompt bytes that are still to be generated.
+    pub fn remaining(&self) -> &[u8] {
+        &self.remaining
+    }
+
+    /// A token is allowed if its text extends the removed bytes or, when
+    /// shorter, is a prefix of them.
+    fn allows(&self, token: i32) -> bool {
+        self.pieces.get(token as usize).is_some_and(|p| {
+            !p.is_empty() && (p.starts_with(&self.remaining) || self.remaining.starts_with(p))
+        })
+    }
+}
+
+impl Constraint for TokenHealing {
+    fn mask(&self, logits: &mut [f32]) {
+        if self.remaining.is_empty() {
+            return;
+        }
+        for (id, logit) in logits.iter_mut().enumerate() {
+            if !self.allows(id as i32) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    fn accept(&mut self, token: i32) -> Result<()> {
+        if self.remaining.is_empty() {
+            return Ok(());
+        }
+        if !self.allows(token) {
+            return Err(LlamaError::Grammar(format!(
+                "token healing: token {token} does not continue the prompt"
+            )));
+        }
+        let n = self.pieces[token as usize].len().min(self.remaining.len());
+        self.remaining.drain(..n);
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.remaining.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::llama2_tokenizer;
+
+    #[test]
+    fn backs_off_and_regenerates_the_removed_text() {
+        let tok = llama2_tokenizer();
+        let mut tokens = tok.encode("Hello wor", true, false).unwrap();
+        let full = tokens.clone();
+        let heal = TokenHealing::new(&tok, &mut tokens, 2);
+        assert_eq!(tokens, full[..full.len() - 2]);
+        let removed: Vec<u8> = full[tokens.len()..]
+            .iter()
+            .flat_map(|&t| tok.piece_bytes(t).unwrap())
+            .collect();
+        assert_eq!(heal.remaining(), removed);
+        assert!(removed.ends_with(b" wor"));
+    }
+
+    #[test]
+    fn allows_tokens_extending_the_prompt() {
+        let tok = llama2_tokenizer();
+        let mut tokens = tok.encode("https:", true, false).unwrap();
+        let mut heal = TokenHealing::new(&tok, &mut tokens, 1);
+        assert_eq!(heal.remaining(), b":");
+
+        let scheme = tok.token_id("://").unwrap();
+        let mut logits = vec![0.0; tok.vocab_size()];
+        heal.mask(&mut logits);
+        assert_eq!(logits[scheme as usize], 0.0);
+        assert_eq!(logits[tok.token_id(":").unwrap() as usize], 0.0);
+        assert_eq!(
+            logits[tok.token_id("/").unwrap() as usize],
+            f32::NEG_INFINITY
+        );
+        assert_eq!(logits[tok.eos_id as usize], f32::NEG_INFINITY);
+
+        assert!(heal.accept(tok.token_id("a").unwrap()).is_err());
+        heal.accept(scheme).unwrap();
+        assert!(heal.is_complete());
+        // Once healed, nothing is masked
+        let mut logits = vec![0.0; tok.vocab_size()];
+        heal.mask(&mut logits);
+        assert!(logits.iter().all(|&l| l == 0.0));
+    }
+
+    #[test]
+    fn partial_tokens_consume_the_removed_text() {
+        let tok = llama2_tokenizer();
+        let mut tokens = vec![tok.bos_id, tok.token_id("://").unwrap()];
+        let mut heal = TokenHealing::new(&tok, &mut tokens, 1);
+        heal.accept(tok.token_id(":").unwrap()).unwrap();
+        assert_eq!(heal.remaining(), b"//");
+        assert!(!heal.is_complete());
+    }
+
+    #[test]
+    fn stops_at_bos_and_control_tokens() {
+        let tok = llama2_tokenizer();
+        let word = tok.token_id(" Hello").unwrap();
+
+        let mut tokens = vec![tok.bos_id, word];
+        let heal = TokenHealing::new(&tok, &mut tokens, 5);
+        assert_eq!(tokens, [tok.bos_id]);
+        assert_eq!(heal.remaining(), b" Hello");
+
+        let mut tokens = vec![tok.bos_id, word, tok.eos_id, word];
+        TokenHealing::new(&tok, &mut tokens, 5);
+        assert_eq!(tokens, [tok.bos_id, word, tok.eos_id]);
+
+        // A prompt of one token is kept to run the model on
+        let mut tokens = vec![word];
+        let heal = TokenHealing::new(&tok, &mut tokens, 5);
+        assert_eq!(tokens, [word]);
+        assert!(heal.is_complete());
+    }
+}
@@ -3,19 +3,51 @@
 //! A minimal implementation of Llama model inference, aligned with
 //! LlamaModel in Hugging Face Transformers.
 
+pub mod beam;
+pub mod cache;
+pub mod chat;
 pub mod config;
+pub mod constraint;
+pub mod contrastive;
 pub mod error;
+pub mod generate;
+pub mod grammar;
+pub mod heal;
+pub mod json;
 pub mod model;
 pub mod ops;
+pub mod regex;
 pub mod sample;
+pub mod schema;
+pub mod sentencepiece;
 pub mod state;
+pub mod stop;
+pub mod tiktoken;
 pub mod tokenizer;
+pub mod tokenizer_json;
+pub mod trainer;
 pub mod weights;
 
+pub use beam::{BeamConfig, Hypothesis};
+pub use cache::CachePolicy;
+pub use chat::{ChatTemplate, Message};
 pub use config::LlamaConfig;
+pub use constraint::Constraint;
+pub use contrastive::ContrastiveConfig;
 pub use error::{LlamaError, Result};
+pub use generate::{Generator, Guidance};
+pub use grammar::{Grammar, GrammarConstraint};
+pub use heal::TokenHealing;
+pub use json::Json;
 pub use model::{forward, load_model};
-pub use sample::sample;
+pub use regex::RegexConstraint;
+pub use sample::{Sampler, SamplerConfig, Stage, TokenLogprob};
+pub use schema::json_schema_to_grammar;
+pub use sentencepiece::{load_sentencepiece, parse_sentencepiece};
 pub use state::LlamaState;
-pub use tokenizer::{Tokenizer, bpe_encode, load_tokenizer};
+pub use stop::StopMatcher;
+pub use tiktoken::{TiktokenTokenizer, load_tiktoken, parse_tiktoken};
+pub use tokenizer::{Detokenizer, Tokenize, Tokenizer, bpe_encode, load_tokenizer, save_tokenizer};
+pub use tokenizer_json::load_tokenizer_json;
+pub use trainer::BpeTrainer;
 pub use weights::{LlamaLayerWeights, LlamaWeights};
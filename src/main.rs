@@ -1,22 +1,89 @@
-use llama_rs::{LlamaState, forward, load_model, load_tokenizer, sample};
-use rand::SeedableRng;
-use rand::rngs::StdRng;
+use llama_rs::sample::Dry;
+use llama_rs::{
+    BeamConfig, BpeTrainer, CachePolicy, ChatTemplate, Constraint, ContrastiveConfig, Detokenizer,
+    Generator, Grammar, GrammarConstraint, Json, LlamaError, LlamaState, Message, RegexConstraint,
+    SamplerConfig, StopMatcher, TokenHealing, Tokenize, json_schema_to_grammar, load_model,
+    load_tiktoken, load_tokenizer, load_tokenizer_json, parse_sentencepiece, parse_tiktoken,
+    save_tokenizer,
+};
 use std::env;
 use std::io::{self, Write};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
 
+    if args.get(1).map(String::as_str) == Some("train-tokenizer") {
+        return train_tokenizer(&args);
+    }
+
     if args.len() < 3 {
         eprintln!(
             "Usage: {} <checkpoint> <tokenizer> [prompt] [options]",
             args[0]
         );
+        eprintln!(
+            "       {} train-tokenizer <corpus.txt> <tokenizer.bin> [options]",
+            args[0]
+        );
         eprintln!("Options:");
         eprintln!("  --temp <float>    Temperature (default: 1.0, 0 = greedy)");
         eprintln!("  --topp <float>    Top-p sampling (default: 0.9)");
+        eprintln!("  --topk <int>      Top-k sampling (default: 0 = off)");
+        eprintln!("  --minp <float>    Min-p sampling (default: 0 = off)");
+        eprintln!("  --typical <float> Locally typical sampling (default: 1 = off)");
+        eprintln!("  --tfs <float>     Tail-free sampling (default: 1 = off)");
+        eprintln!("  --samplers <list> Comma-separated stage order (see README)");
+        eprintln!("  --repeat-penalty <float>     Repetition penalty (default: 1 = off)");
+        eprintln!("  --repeat-last-n <int>        Penalty window in tokens (default: 64)");
+        eprintln!("  --frequency-penalty <float>  Frequency penalty (default: 0 = off)");
+        eprintln!("  --presence-penalty <float>   Presence penalty (default: 0 = off)");
+        eprintln!("  --penalize-nl                Also penalize newline tokens");
+        eprintln!("  --dry-multiplier <float>     DRY penalty scale (default: 0 = off)");
+        eprintln!("  --dry-base <float>           DRY penalty growth (default: 1.75)");
+        eprintln!("  --dry-allowed-length <int>   Longest unpenalized repeat (default: 2)");
+        eprintln!("  --dry-last-n <int>           DRY search window (default: 0 = all)");
+        eprintln!("  --dry-breaker <str>          DRY sequence breaker, repeatable");
+        eprintln!("  --xtc-probability <float>    Chance to apply XTC (default: 0 = off)");
+        eprintln!("  --xtc-threshold <float>      XTC probability threshold (default: 0.1)");
+        eprintln!("  --logit-bias <tok>=<float>   Bias a token id or string, repeatable");
+        eprintln!("  --ban <tok>                  Never sample a token id or string, repeatable");
+        eprintln!("  --min-tokens <int>           Mask EOS until this many tokens (default: 0)");
+        eprintln!("  --stop <str>                 Stop when the output contains this, repeatable");
+        eprintln!(
+            "  --eos <tok>                  End-of-generation token, repeatable (default: 2)"
+        );
+        eprintln!("  --grammar <gbnf>             Constrain output to a GBNF grammar");
+        eprintln!("  --grammar-file <path>        Load the GBNF grammar from a file");
+        eprintln!("  --json-schema <json>         Constrain output to JSON matching a schema");
+        eprintln!("  --json-schema-file <path>    Load the JSON Schema from a file");
+        eprintln!("  --regex <pattern>            Constrain output to match a regex");
+        eprintln!("  --mirostat <int>             Mirostat version, 0 = off (default: 0)");
+        eprintln!("  --mirostat-tau <float>       Mirostat target surprise (default: 5.0)");
+        eprintln!("  --mirostat-eta <float>       Mirostat learning rate (default: 0.1)");
+        eprintln!("  --cfg-negative-prompt <str>  Negative prompt for classifier-free guidance");
+        eprintln!("  --cfg-scale <float>          Guidance strength, 1.0 = off (default: 1.0)");
+        eprintln!("  --beams <int>                Beam search width, 0 = sample (default: 0)");
+        eprintln!("  --length-penalty <float>     Beam score length exponent (default: 1.0)");
+        eprintln!("  --early-stopping             Stop once <beams> hypotheses finish");
+        eprintln!("  --n-best <int>               Beam hypotheses to print (default: 1)");
+        eprintln!(
+            "  --penalty-alpha <float>      Contrastive search over --topk (default 4), 0 = off"
+        );
+        eprintln!("  --special                    Parse special tokens such as </s> in prompts");
+        eprintln!("  --chat <name>                Chat template: llama2, llama3, chatml, mistral");
+        eprintln!("  --system <str>               System message for --chat");
+        eprintln!("  --messages <file>            JSON [{{role, content}}] history for --chat");
+        eprintln!("  --token-healing <int>        Prompt tokens to back off and regenerate");
+        eprintln!("  --json                       Print the result as JSON with token logprobs");
+        eprintln!(
+            "  --logprobs <int>             Top alternatives per token in --json (default: 0)"
+        );
         eprintln!("  --steps <int>     Max tokens to generate (default: 256)");
         eprintln!("  --seed <int>      Random seed (default: 0)");
+        eprintln!("  --cache <policy>  KV cache policy: full, streaming, h2o (default: full)");
+        eprintln!("  --sink <int>      Attention sink tokens for streaming (default: 4)");
+        eprintln!("  --window <int>    Recent tokens kept (default: seq_len-sink, budget/2)");
+        eprintln!("  --budget <int>    Total cached tokens for h2o (default: seq_len)");
         std::process::exit(1);
     }
 
@@ -25,34 +92,318 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let prompt = args.get(3).map(|s| s.as_str()).unwrap_or("");
 
     // Parse optional arguments
-    let mut temp = 1.0;
-    let mut topp = 0.9;
+    let mut sampling = SamplerConfig::default();
     let mut steps = 256usize;
-    let mut seed = 0u64;
+    let mut beam = BeamConfig {
+        width: 0,
+        ..Default::default()
+    };
+    let mut dry_breakers: Vec<String> = Vec::new();
+    let mut biases: Vec<(String, f32)> = Vec::new();
+    let mut stops: Vec<String> = Vec::new();
+    let mut cfg_negative_prompt: Option<String> = None;
+    let mut cfg_scale = 1.0f32;
+    let mut penalty_alpha = 0.0f32;
+    let mut token_healing = 0usize;
+    let mut json_output = false;
+    let mut parse_special = false;
+    let mut chat: Option<ChatTemplate> = None;
+    let mut system: Option<String> = None;
+    let mut history: Vec<Message> = Vec::new();
+    let mut n_logprobs = 0usize;
+    let mut eos_specs: Vec<String> = Vec::new();
+    let mut grammar_src: Option<String> = None;
+    let mut schema_src: Option<String> = None;
+    let mut regex_src: Option<String> = None;
+    let mut cache = String::from("full");
+    let mut n_sink = 4usize;
+    let mut window: Option<usize> = None;
+    let mut budget: Option<usize> = None;
 
     let mut i = 4;
     while i < args.len() {
         match args[i].as_str() {
             "--temp" => {
-                temp = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(1.0);
+                sampling.temperature = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(1.0);
                 i += 2;
             }
             "--topp" => {
-                topp = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0.9);
+                sampling.top_p = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0.9);
+                i += 2;
+            }
+            "--topk" => {
+                sampling.top_k = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0);
+                i += 2;
+            }
+            "--minp" => {
+                sampling.min_p = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0.0);
+                i += 2;
+            }
+            "--typical" => {
+                sampling.typical_p = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(1.0);
+                i += 2;
+            }
+            "--tfs" => {
+                sampling.tfs_z = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(1.0);
+                i += 2;
+            }
+            "--samplers" => {
+                if let Some(order) = args.get(i + 1) {
+                    sampling.set_order(order);
+                }
                 i += 2;
             }
             "--steps" => {
                 steps = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(256);
                 i += 2;
             }
+            "--repeat-penalty" => {
+                sampling.penalties.repeat =
+                    args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(1.0);
+                i += 2;
+            }
+            "--repeat-last-n" => {
+                sampling.penalties.last_n =
+                    args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(64);
+                i += 2;
+            }
+            "--frequency-penalty" => {
+                sampling.penalties.frequency =
+                    args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0.0);
+                i += 2;
+            }
+            "--presence-penalty" => {
+                sampling.penalties.presence =
+                    args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0.0);
+                i += 2;
+            }
+            "--penalize-nl" => {
+                sampling.penalize_nl = true;
+                i += 1;
+            }
+            "--dry-multiplier" => {
+                sampling.dry.multiplier =
+                    args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0.0);
+                i += 2;
+            }
+            "--dry-base" => {
+                sampling.dry.base = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(1.75);
+                i += 2;
+            }
+            "--dry-allowed-length" => {
+                sampling.dry.allowed_length =
+                    args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(2);
+                i += 2;
+            }
+            "--dry-last-n" => {
+                sampling.dry.last_n = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0);
+                i += 2;
+            }
+            "--dry-breaker" => {
+                if let Some(b) = args.get(i + 1) {
+                    dry_breakers.push(b.replace("\\n", "\n"));
+                }
+                i += 2;
+            }
+            "--xtc-probability" => {
+                sampling.xtc_probability =
+                    args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0.0);
+                i += 2;
+            }
+            "--xtc-threshold" => {
+                sampling.xtc_threshold =
+                    args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0.1);
+                i += 2;
+            }
+            "--logit-bias" => {
+                let spec = args.get(i + 1).map(String::as_str).unwrap_or("");
+                match spec
+                    .rsplit_once('=')
+                    .and_then(|(tok, bias)| Some((tok, bias.parse::<f32>().ok()?)))
+                {
+                    Some((tok, bias)) if !tok.is_empty() && bias < f32::INFINITY => {
+                        biases.push((tok.to_string(), bias));
+                    }
+                    _ => {
+                        return Err(format!(
+                            "--logit-bias expects <tok>=<float> with a finite or -inf bias, got {spec:?}"
+                        )
+                        .into());
+                    }
+                }
+                i += 2;
+            }
+            "--ban" => {
+                if let Some(tok) = args.get(i + 1) {
+                    biases.push((tok.clone(), f32::NEG_INFINITY));
+                }
+                i += 2;
+            }
+            "--stop" => {
+                stops.extend(args.get(i + 1).cloned());
+                i += 2;
+            }
+            "--eos" => {
+                eos_specs.extend(args.get(i + 1).cloned());
+                i += 2;
+            }
+            "--min-tokens" => {
+                sampling.min_tokens = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0);
+                i += 2;
+            }
+            "--grammar" => {
+                grammar_src = args.get(i + 1).cloned();
+                i += 2;
+            }
+            "--grammar-file" => {
+                if let Some(path) = args.get(i + 1) {
+                    grammar_src = Some(std::fs::read_to_string(path)?);
+                }
+                i += 2;
+            }
+            "--json-schema" => {
+                schema_src = args.get(i + 1).cloned();
+                i += 2;
+            }
+            "--json-schema-file" => {
+                if let Some(path) = args.get(i + 1) {
+                    schema_src = Some(std::fs::read_to_string(path)?);
+                }
+                i += 2;
+            }
+            "--regex" => {
+                regex_src = args.get(i + 1).cloned();
+                i += 2;
+            }
+            "--mirostat" => {
+                sampling.mirostat = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0);
+                i += 2;
+            }
+            "--mirostat-tau" => {
+                sampling.mirostat_tau = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(5.0);
+                i += 2;
+            }
+            "--mirostat-eta" => {
+                sampling.mirostat_eta = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0.1);
+                i += 2;
+            }
+            "--cfg-negative-prompt" => {
+                cfg_negative_prompt = args.get(i + 1).cloned();
+                i += 2;
+            }
+            "--cfg-scale" => {
+                cfg_scale = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(1.0);
+                i += 2;
+            }
+            "--special" => {
+                parse_special = true;
+                i += 1;
+            }
+            "--chat" => {
+                if let Some(name) = args.get(i + 1) {
+                    chat = Some(ChatTemplate::from_name(name)?);
+                }
+                i += 2;
+            }
+            "--system" => {
+                system = args.get(i + 1).cloned();
+                i += 2;
+            }
+            "--messages" => {
+                if let Some(path) = args.get(i + 1) {
+                    history = parse_messages(&std::fs::read_to_string(path)?)?;
+                }
+                i += 2;
+            }
+            "--token-healing" => {
+                token_healing = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0);
+                i += 2;
+            }
+            "--json" => {
+                json_output = true;
+                i += 1;
+            }
+            "--logprobs" => {
+                n_logprobs = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0);
+                i += 2;
+            }
+            "--penalty-alpha" => {
+                penalty_alpha = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0.0);
+                i += 2;
+            }
+            "--beams" => {
+                beam.width = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0);
+                i += 2;
+            }
+            "--length-penalty" => {
+                beam.length_penalty = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(1.0);
+                i += 2;
+            }
+            "--early-stopping" => {
+                beam.early_stopping = true;
+                i += 1;
+            }
+            "--n-best" => {
+                beam.n_best = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(1);
+                i += 2;
+            }
             "--seed" => {
-                seed = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0);
+                sampling.seed = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(0);
+                i += 2;
+            }
+            "--cache" => {
+                cache = args.get(i + 1).cloned().unwrap_or(cache);
+                i += 2;
+            }
+            "--sink" => {
+                n_sink = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(4);
+                i += 2;
+            }
+            "--window" => {
+                window = args.get(i + 1).and_then(|s| s.parse().ok());
+                i += 2;
+            }
+            "--budget" => {
+                budget = args.get(i + 1).and_then(|s| s.parse().ok());
                 i += 2;
             }
             _ => i += 1,
         }
     }
 
+    if chat.is_none() && (system.is_some() || !history.is_empty()) {
+        return Err("--system and --messages need a --chat template".into());
+    }
+
+    // Beam and contrastive search rank the raw model probabilities and print
+    // the finished text, so options that shape sampling or stream output do
+    // not apply to them
+    let search = match (beam.width > 0, penalty_alpha > 0.0) {
+        (true, true) => return Err("use only one of --beams and --penalty-alpha".into()),
+        (true, false) => Some("--beams"),
+        (false, true) => Some("--penalty-alpha"),
+        (false, false) => None,
+    };
+    if let Some(search) = search {
+        let unsupported = [
+            ("--grammar", grammar_src.is_some()),
+            ("--json-schema", schema_src.is_some()),
+            ("--regex", regex_src.is_some()),
+            ("--stop", !stops.is_empty()),
+            ("--json", json_output),
+            ("--logit-bias and --ban", !biases.is_empty()),
+            ("--repeat-penalty", sampling.penalties.repeat != 1.0),
+            ("--frequency-penalty", sampling.penalties.frequency != 0.0),
+            ("--presence-penalty", sampling.penalties.presence != 0.0),
+            ("--dry-multiplier", sampling.dry.multiplier != 0.0),
+            ("--cfg-negative-prompt", cfg_negative_prompt.is_some()),
+            ("--token-healing", token_healing > 0),
+        ];
+        if let Some((flag, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(format!("{flag} cannot be combined with {search}").into());
+        }
+    }
+
     // Load model and tokenizer
     eprintln!("Loading model from: {}", checkpoint_path);
     let (config, weights) = load_model(checkpoint_path)?;
@@ -61,52 +412,401 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         config.dim, config.n_layers, config.n_heads, config.vocab_size
     );
 
-    let tokenizer = load_tokenizer(tokenizer_path, config.vocab_size as usize)?;
-    eprintln!("Loaded tokenizer with {} tokens", tokenizer.vocab.len());
+    let vocab_size = config.vocab_size as usize;
+    let tokenizer: Box<dyn Tokenize> = if tokenizer_path.ends_with(".json") {
+        Box::new(load_tokenizer_json(tokenizer_path)?)
+    } else if tokenizer_path.ends_with(".tiktoken") {
+        Box::new(load_tiktoken(tokenizer_path, vocab_size)?)
+    } else if tokenizer_path.ends_with(".model") {
+        // Llama 3 ships a tiktoken rank file under the SentencePiece name; a
+        // SentencePiece protobuf starts with the tag of its first piece
+        let data = std::fs::read(tokenizer_path)?;
+        if data.first() == Some(&0x0a) {
+            Box::new(parse_sentencepiece(&data)?)
+        } else {
+            Box::new(parse_tiktoken(&String::from_utf8(data)?, vocab_size)?)
+        }
+    } else {
+        Box::new(load_tokenizer(tokenizer_path, vocab_size)?)
+    };
+    let tokenizer = tokenizer.as_ref();
+    eprintln!("Loaded tokenizer with {} tokens", tokenizer.vocab_size());
+    // Models may pad the embedding table past the tokenizer, but every token
+    // needs a logit
+    if tokenizer.vocab_size() > vocab_size {
+        return Err(format!(
+            "tokenizer has {} tokens but the model only {vocab_size}",
+            tokenizer.vocab_size()
+        )
+        .into());
+    }
 
-    // Initialize state and RNG
-    let mut state = LlamaState::new(&config);
-    let mut rng = StdRng::seed_from_u64(seed);
+    // Initialize state and sampler
+    let seq_len = config.seq_len as usize;
+    let policy = match cache.as_str() {
+        "full" => CachePolicy::Full,
+        "streaming" => CachePolicy::Streaming {
+            n_sink,
+            window: window.unwrap_or(seq_len.saturating_sub(n_sink)),
+        },
+        "h2o" => {
+            let budget = budget.unwrap_or(seq_len);
+            CachePolicy::HeavyHitter {
+                budget,
+                n_recent: window.unwrap_or(budget / 2),
+            }
+        }
+        other => return Err(format!("unknown cache policy: {other}").into()),
+    };
+    let state = LlamaState::with_cache_policy(&config, policy)?;
+    if policy == CachePolicy::Full {
+        steps = steps.min(seq_len);
+    }
+    if sampling.dry.multiplier != 0.0 {
+        sampling.dry.breakers = if dry_breakers.is_empty() {
+            Dry::breaker_ids(tokenizer, &Dry::DEFAULT_BREAKERS)
+        } else {
+            Dry::breaker_ids(tokenizer, &dry_breakers)
+        };
+    }
+    for (tok, bias) in &biases {
+        for id in resolve_tokens(tokenizer, tok)? {
+            *sampling.logit_bias.entry(id).or_default() += bias;
+        }
+    }
+    sampling.eos_tokens = vec![tokenizer.eos_id()];
+    sampling.eos_tokens.extend(
+        chat.and_then(|c| c.end_of_turn())
+            .and_then(|name| tokenizer.token_id(name)),
+    );
+    if !eos_specs.is_empty() {
+        sampling.eos_tokens.clear();
+        for spec in &eos_specs {
+            match resolve_tokens(tokenizer, spec)?[..] {
+                [id] => sampling.eos_tokens.push(id),
+                ref ids => {
+                    return Err(format!(
+                        "--eos {spec:?} must be a single token, but encodes to {ids:?}"
+                    )
+                    .into());
+                }
+            }
+        }
+    }
+    let sampler = sampling.build_for(tokenizer)?;
+
+    // Output constraint, applied to the logits before sampling
+    let n_constraints = [
+        grammar_src.is_some(),
+        schema_src.is_some(),
+        regex_src.is_some(),
+    ]
+    .iter()
+    .filter(|&&set| set)
+    .count();
+    if n_constraints > 1 {
+        return Err("use only one of --grammar, --json-schema and --regex".into());
+    }
+    let grammar = match (grammar_src, schema_src) {
+        (Some(src), _) => Some(Grammar::parse(&src)?),
+        (None, Some(schema)) => Some(json_schema_to_grammar(&schema)?),
+        (None, None) => None,
+    };
+    let constraint: Option<Box<dyn Constraint>> = match (grammar, regex_src) {
+        (Some(g), _) => Some(Box::new(GrammarConstraint::new(
+            g,
+            tokenizer,
+            &sampling.eos_tokens,
+        ))),
+        (None, Some(pattern)) => Some(Box::new(RegexConstraint::new(
+            &pattern,
+            tokenizer,
+            &sampling.eos_tokens,
+        )?)),
+        (None, None) => None,
+    };
 
     // Encode prompt
-    let tokens = tokenizer.encode(prompt, true, false)?;
+    let encode = |text: &str| {
+        if parse_special {
+            tokenizer.encode_with_special(text, true, false)
+        } else {
+            tokenizer.encode(text, true, false)
+        }
+    };
+    let mut tokens = match chat {
+        Some(template) => {
+            let mut messages = Vec::new();
+            if let Some(system) = &system {
+                messages.push(Message::new("system", system));
+            }
+            messages.extend(history);
+            if !prompt.is_empty() {
+                messages.push(Message::new("user", prompt));
+            }
+            template.encode(tokenizer, &messages)?
+        }
+        None => encode(prompt)?,
+    };
     eprintln!("Prompt tokens: {:?}", tokens);
 
-    // Generate
-    let mut pos = 0i32;
-    let mut token = tokens[0];
+    if penalty_alpha > 0.0 {
+        let contrastive = ContrastiveConfig {
+            top_k: match sampling.top_k {
+                0 => ContrastiveConfig::default().top_k,
+                k => k,
+            },
+            alpha: penalty_alpha,
+            max_tokens: (steps + 1).saturating_sub(tokens.len()),
+            eos_tokens: sampling.eos_tokens.clone(),
+        };
+        let out = contrastive.search(&tokens, &config, &weights, state)?;
+        println!(
+            "{}",
+            tokenizer.decode_all(&[tokens.as_slice(), &out].concat())
+        );
+        return Ok(());
+    }
+
+    if beam.width > 0 {
+        beam.max_tokens = (steps + 1).saturating_sub(tokens.len());
+        beam.eos_tokens = sampling.eos_tokens.clone();
+        let hyps = beam.search(&tokens, &config, &weights, state)?;
+        for (rank, hyp) in hyps.iter().enumerate() {
+            if hyps.len() > 1 {
+                eprintln!(
+                    "#{} score {:.4} logprob {:.4}",
+                    rank + 1,
+                    hyp.score,
+                    hyp.log_prob
+                );
+            }
+            println!("{}", tokenizer.decode_all(&hyp.tokens));
+        }
+        return Ok(());
+    }
+
+    // Token healing: drop the end of the prompt and let the model regenerate it
+    let mut constraint = constraint;
+    let mut removed = Vec::new();
+    if token_healing > 0 {
+        if constraint.is_some() {
+            return Err("--token-healing cannot be combined with a grammar or regex".into());
+        }
+        let full = tokens.clone();
+        constraint = Some(Box::new(TokenHealing::new(
+            tokenizer,
+            &mut tokens,
+            token_healing,
+        )));
+        removed = full[tokens.len()..].to_vec();
+        eprintln!("Healing prompt tokens: {:?}", removed);
+    }
+
+    // Echo the prompt, then generate. Text regenerated by token healing is
+    // echoed with the prompt and skipped in the output.
+    let mut detok = Detokenizer::new(tokenizer);
+    let mut echo: String = tokens.iter().map(|&t| detok.push(t)).collect();
+    let healed: String = {
+        let mut detok = detok.clone();
+        removed.iter().map(|&t| detok.push(t)).collect()
+    };
+    echo.push_str(&healed);
+    let mut skip = healed.len();
+    if !json_output && chat.is_none() {
+        print!("{echo}");
+        io::stdout().flush()?;
+    }
 
-    for step in 0..steps {
-        forward(token, pos, &config, &mut state, &weights);
+    let eos_tokens = sampler.eos_tokens.clone();
+    let mut generator = Generator::new(&config, &weights, state, sampler);
+    generator.constraint = constraint;
+    if let Some(negative) = cfg_negative_prompt {
+        let negative = encode(&negative)?;
+        let state = LlamaState::with_cache_policy(&config, policy)?;
+        generator.set_guidance(&negative, cfg_scale, state)?;
+    }
+    generator.prompt(&tokens)?;
 
-        let next_token = if step < tokens.len() - 1 {
-            tokens[step + 1]
+    // Constrained output is only printed once it is known to be complete
+    let constrained = generator.constraint.is_some() && token_healing == 0;
+    let mut stop = StopMatcher::new(&stops);
+    let mut content = Vec::new();
+    let mut records = Vec::new();
+    let mut finish_reason = "length";
+    for _ in 0..(steps + 1).saturating_sub(tokens.len()) {
+        let next_token = if json_output {
+            let lp = generator.next_token_logprobs(n_logprobs)?;
+            let id = lp.id;
+            records.push(lp);
+            id
         } else {
-            sample(&mut state.logits, temp, topp, &mut rng)
+            generator.next_token()?
         };
+        if eos_tokens.contains(&next_token) {
+            finish_reason = "eos";
+            break;
+        }
 
-        // Decode and print token
-        if let Some(piece) = tokenizer.decode(next_token) {
-            // Handle special byte tokens (encoded as <0xXX>)
-            if piece.starts_with("<0x") && piece.ends_with('>') && piece.len() == 6 {
-                if let Ok(byte) = u8::from_str_radix(&piece[3..5], 16) {
-                    print!("{}", byte as char);
-                }
-            } else {
-                print!("{}", piece);
-            }
+        // Decode and print token, holding back text that may start a stop string
+        let piece = detok.push(next_token);
+        let n = skip.min(piece.len());
+        skip -= n;
+        let mut out = Vec::new();
+        let stopped = stop.push(&piece.as_bytes()[n..], &mut out);
+        if json_output || constrained {
+            content.extend(out);
+        } else {
+            io::stdout().write_all(&out)?;
             io::stdout().flush()?;
         }
-
-        // Check for EOS
-        if next_token == 2 {
+        if stopped {
+            finish_reason = "stop";
             break;
         }
+    }
+
+    // Text held back for a stop string that never completed
+    let mut rest = Vec::new();
+    if finish_reason != "stop" {
+        stop.push(detok.flush().as_bytes(), &mut rest);
+        stop.flush(&mut rest);
+    }
+    // Accepting EOS already proved the output complete
+    if constrained
+        && finish_reason != "eos"
+        && generator
+            .constraint
+            .as_ref()
+            .is_some_and(|c| !c.is_complete())
+    {
+        return Err(format!(
+            "generation ended ({finish_reason}) before the output matched the grammar or regex"
+        )
+        .into());
+    }
+    if json_output {
+        content.extend(rest);
+        let text = |id: i32| {
+            let bytes = tokenizer.piece_bytes(id).unwrap_or_default();
+            Json::String(String::from_utf8_lossy(&bytes).into_owned())
+        };
+        let tokens = records
+            .iter()
+            .map(|lp| {
+                let top = lp
+                    .top
+                    .iter()
+                    .map(|&(id, logprob)| {
+                        Json::Object(vec![
+                            ("id".into(), Json::Number(id as f64)),
+                            ("text".into(), text(id)),
+                            ("logprob".into(), Json::Number(logprob as f64)),
+                        ])
+                    })
+                    .collect();
+                Json::Object(vec![
+                    ("id".into(), Json::Number(lp.id as f64)),
+                    ("text".into(), text(lp.id)),
+                    ("logprob".into(), Json::Number(lp.logprob as f64)),
+                    ("top_logprobs".into(), Json::Array(top)),
+                ])
+            })
+            .collect();
+        let output = Json::Object(vec![
+            ("prompt".into(), Json::String(prompt.to_string())),
+            (
+                "content".into(),
+                Json::String(String::from_utf8_lossy(&content).into_owned()),
+            ),
+            ("finish_reason".into(), Json::String(finish_reason.into())),
+            ("tokens".into(), Json::Array(tokens)),
+        ]);
+        println!("{output}");
+    } else {
+        io::stdout().write_all(&content)?;
+        io::stdout().write_all(&rest)?;
+        println!();
+    }
+    Ok(())
+}
 
-        token = next_token;
-        pos += 1;
+/// Train a BPE vocabulary on a text file and write it as `tokenizer.bin`.
+fn train_tokenizer(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if args.len() < 4 {
+        eprintln!(
+            "Usage: {} train-tokenizer <corpus.txt> <tokenizer.bin> [options]",
+            args[0]
+        );
+        eprintln!("Options:");
+        eprintln!("  --vocab-size <int>            Total tokens (default: 32000)");
+        eprintln!("  --character-coverage <float>  Characters not left to bytes (default: 0.9995)");
+        eprintln!("  --max-piece-len <int>         Longest piece in characters (default: 16)");
+        std::process::exit(1);
     }
 
-    println!();
+    let mut trainer = BpeTrainer::default();
+    let mut i = 4;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--vocab-size" => trainer.vocab_size = option_value(args, i)?,
+            "--character-coverage" => trainer.character_coverage = option_value(args, i)?,
+            "--max-piece-len" => trainer.max_piece_len = option_value(args, i)?,
+            _ => {
+                i += 1;
+                continue;
+            }
+        }
+        i += 2;
+    }
+
+    let corpus = std::fs::read_to_string(&args[2])?;
+    let tokenizer = trainer.train(&corpus)?;
+    save_tokenizer(&tokenizer, &args[3])?;
+    eprintln!("Wrote {} tokens to {}", tokenizer.vocab.len(), args[3]);
     Ok(())
 }
+
+/// Parse the value following the option at `args[i]`.
+fn option_value<T: std::str::FromStr>(
+    args: &[String],
+    i: usize,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let value = args.get(i + 1).map_or("", String::as_str);
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {value:?}", args[i]).into())
+}
+
+/// Parse a JSON array of `{"role": ..., "content": ...}` messages.
+fn parse_messages(src: &str) -> llama_rs::Result<Vec<Message>> {
+    let invalid = || llama_rs::LlamaError::Config("messages must be [{role, content}]".into());
+    Json::parse(src)?
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|m| {
+            let field = |key| m.get(key).and_then(Json::as_str).ok_or_else(invalid);
+            Ok(Message::new(field("role")?, field("content")?))
+        })
+        .collect()
+}
+
+/// Resolve a CLI token spec: a numeric id, an exact vocabulary piece such as
+/// `<|eot_id|>`, or text encoded with `Tokenize::encode_continuation`, so
+/// that no dummy-prefix space is added.
+fn resolve_tokens(tokenizer: &dyn Tokenize, spec: &str) -> llama_rs::Result<Vec<i32>> {
+    if let Ok(id) = spec.parse::<i32>() {
+        if id < 0 || id as usize >= tokenizer.vocab_size() {
+            return Err(LlamaError::Config(format!(
+                "token id {id} is out of range for a vocabulary of {}",
+                tokenizer.vocab_size()
+            )));
+        }
+        return Ok(vec![id]);
+    }
+    match tokenizer.token_id(spec) {
+        Some(id) => Ok(vec![id]),
+        None => tokenizer.encode_continuation(spec),
+    }
+}
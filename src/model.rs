@@ -32,6 +32,12 @@ pub fn load_model<P: AsRef<Path>>(path: P) -> Result<(LlamaConfig, LlamaWeights)
 }
 
 /// Perform a single-token forward pass, aligned with LlamaModel.forward.
+///
+/// Under an evicting [`CachePolicy`](crate::cache::CachePolicy), `pos` only
+/// counts generated tokens; RoPE uses the token's position in cache order.
+///
+/// On return `state.x` holds the token's last hidden state after the final
+/// norm, and `state.logits` the next-token logits.
 pub fn forward(
     token: i32,
     pos: i32,
@@ -40,6 +46,12 @@ pub fn forward(
     weights: &LlamaWeights,
 ) {
     let dim = config.dim as usize;
+    let slot = state.next_slot(pos, config);
+    let positions: Vec<usize> = if state.rope.is_empty() {
+        Vec::new()
+    } else {
+        (0..state.cache_len).map(|t| state.position(t)).collect()
+    };
 
     // Token embedding
     let emb_offset = (token as usize) * dim;
@@ -49,7 +61,7 @@ pub fn forward(
 
     // Decoder layers
     for l in 0..config.n_layers as usize {
-        attention(l, pos, config, state, &weights.layers[l]);
+        attention(l, slot, &positions, config, state, &weights.layers[l]);
         mlp(config, state, &weights.layers[l]);
     }
 
@@ -62,15 +74,17 @@ pub fn forward(
 }
 
 /// Self-attention for one layer, aligned with LlamaAttention.forward.
+///
+/// Attends to the occupied cache slots; `positions` holds the RoPE position
+/// of each slot when keys are cached unrotated, and is empty otherwise.
 fn attention(
     layer_idx: usize,
-    pos: i32,
+    slot: usize,
+    positions: &[usize],
     config: &LlamaConfig,
     state: &mut LlamaState,
     layer_weights: &LlamaLayerWeights,
 ) {
-    let _dim = config.dim as usize;
-    let n_heads = config.n_heads as usize;
     let head_size = config.head_size();
     let kv_dim = config.kv_dim();
     let group_size = config.group_size();
@@ -83,61 +97,77 @@ fn attention(
     matmul(&mut state.k, &state.xb, &layer_weights.k_proj);
     matmul(&mut state.v, &state.xb, &layer_weights.v_proj);
 
-    // Apply RoPE
-    apply_rotary_emb(&mut state.q, pos, head_size);
-    apply_rotary_emb(&mut state.k, pos, head_size);
+    // Apply RoPE; under eviction keys are cached unrotated and rotated by
+    // their current position below
+    let pos = positions.get(slot).copied().unwrap_or(slot);
+    apply_rotary_emb(&mut state.q, pos as i32, head_size);
+    if state.rope.is_empty() {
+        apply_rotary_emb(&mut state.k, slot as i32, head_size);
+    }
 
     // Cache K and V
-    let cache_offset = (pos as usize) * kv_dim;
+    let cache_offset = slot * kv_dim;
     state.key_cache[layer_idx][cache_offset..cache_offset + kv_dim].copy_from_slice(&state.k);
     state.value_cache[layer_idx][cache_offset..cache_offset + kv_dim].copy_from_slice(&state.v);
 
-    // Multi-head attention (parallelized)
+    // Multi-head attention (parallelized), writing scores into state.att
     let key_cache = &state.key_cache[layer_idx];
     let value_cache = &state.value_cache[layer_idx];
+    let q_all = &state.q;
+    let rope = &state.rope;
+    let half = head_size / 2;
+    let n = state.cache_len;
 
-    // Collect results from parallel computation
-    let head_outputs: Vec<Vec<f32>> = (0..n_heads)
-        .into_par_iter()
-        .map(|h| {
-            let q_off = h * head_size;
-            let q = &state.q[q_off..q_off + head_size];
+    state
+        .xb
+        .par_chunks_mut(head_size)
+        .zip(state.att.par_iter_mut())
+        .enumerate()
+        .for_each(|(h, (out, att))| {
+            let q = &q_all[h * head_size..(h + 1) * head_size];
             let kv_h = h / group_size;
 
             // Compute attention scores
-            let mut att = vec![0.0f32; (pos + 1) as usize];
-            for t in 0..=pos as usize {
+            let att = &mut att[..n];
+            for (t, score_out) in att.iter_mut().enumerate() {
                 let k_off = t * kv_dim + kv_h * head_size;
                 let k = &key_cache[k_off..k_off + head_size];
 
                 let mut score = 0.0f32;
-                for i in 0..head_size {
-                    score += q[i] * k[i];
+                if rope.is_empty() {
+                    for i in 0..head_size {
+                        score += q[i] * k[i];
+                    }
+                } else {
+                    let p = positions[t];
+                    let rot = &rope[p * half..(p + 1) * half];
+                    for ((q, k), &(cos, sin)) in q.chunks_exact(2).zip(k.chunks_exact(2)).zip(rot) {
+                        score +=
+                            q[0] * (k[0] * cos - k[1] * sin) + q[1] * (k[0] * sin + k[1] * cos);
+                    }
                 }
-                att[t] = score / (head_size as f32).sqrt();
+                *score_out = score / (head_size as f32).sqrt();
             }
 
             // Softmax
-            softmax(&mut att);
+            softmax(att);
 
             // Weighted sum of values
-            let mut out = vec![0.0f32; head_size];
-            for t in 0..=pos as usize {
+            out.fill(0.0);
+            for (t, &a) in att.iter().enumerate() {
                 let v_off = t * kv_dim + kv_h * head_size;
                 let v = &value_cache[v_off..v_off + head_size];
-                let a = att[t];
                 for i in 0..head_size {
                     out[i] += a * v[i];
                 }
             }
-            out
-        })
-        .collect();
-
-    // Gather results into xb
-    for (h, out) in head_outputs.into_iter().enumerate() {
-        let xb_off = h * head_size;
-        state.xb[xb_off..xb_off + head_size].copy_from_slice(&out);
+        });
+
+    // Accumulate attention mass per cached token for eviction
+    if state.cache_policy.evicts() {
+        for att in &state.att {
+            accum(&mut state.att_mass[..n], &att[..n]);
+        }
     }
 
     // Output projection
@@ -165,3 +195,128 @@ fn mlp(_config: &LlamaConfig, state: &mut LlamaState, layer_weights: &LlamaLayer
     // Residual add
     accum(&mut state.x, &state.xb);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CachePolicy;
+    use crate::ops::softmax;
+
+    fn config(n_layers: i32) -> LlamaConfig {
+        LlamaConfig {
+            dim: 16,
+            hidden_dim: 32,
+            n_layers,
+            n_heads: 4,
+            n_kv_heads: 2,
+            vocab_size: 32,
+            seq_len: 16,
+        }
+    }
+
+    fn run(
+        config: &LlamaConfig,
+        weights: &LlamaWeights,
+        policy: CachePolicy,
+        tokens: &[i32],
+    ) -> LlamaState {
+        let mut state = LlamaState::with_cache_policy(config, policy).unwrap();
+        for (pos, &token) in tokens.iter().enumerate() {
+            forward(token, pos as i32, config, &mut state, weights);
+        }
+        state
+    }
+
+    fn max_diff(a: &[f32], b: &[f32]) -> f32 {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn streaming_matches_full_cache_before_eviction() {
+        let config = config(2);
+        let weights = LlamaWeights::random(&config, 1);
+        let tokens: Vec<i32> = (0..12).map(|i| (i * 7) % 32).collect();
+        let full = run(&config, &weights, CachePolicy::Full, &tokens);
+        let streaming = CachePolicy::Streaming {
+            n_sink: 2,
+            window: 14,
+        };
+        let streamed = run(&config, &weights, streaming, &tokens);
+        assert!(max_diff(&full.logits, &streamed.logits) < 1e-4);
+    }
+
+    #[test]
+    fn evicted_cache_matches_fresh_run_on_kept_tokens() {
+        // With one layer the cached keys and values depend only on the token,
+        // so after any number of evictions the cache must equal a fresh run
+        // over the sinks and the window
+        let config = config(1);
+        let weights = LlamaWeights::random(&config, 2);
+        let tokens: Vec<i32> = (0..200).map(|i| (i * 13 + i / 5) % 32).collect();
+        let policy = CachePolicy::Streaming {
+            n_sink: 2,
+            window: 6,
+        };
+        let streamed = run(&config, &weights, policy, &tokens);
+        let kept = [&tokens[..2], &tokens[tokens.len() - 6..]].concat();
+        let fresh = run(&config, &weights, CachePolicy::Full, &kept);
+        assert!(max_diff(&fresh.logits, &streamed.logits) < 1e-4);
+    }
+
+    #[test]
+    fn streaming_perplexity_stays_stable_past_seq_len() {
+        let config = config(2);
+        let weights = LlamaWeights::random(&config, 3);
+        let seq_len = config.seq_len as usize;
+        let tokens: Vec<i32> = (0..6 * seq_len as i32).map(|i| (i * 5) % 11).collect();
+        let policy = CachePolicy::Streaming {
+            n_sink: 4,
+            window: 12,
+        };
+        let mut state = LlamaState::with_cache_policy(&config, policy).unwrap();
+        let mut nll = Vec::new();
+        for (pos, pair) in tokens.windows(2).enumerate() {
+            forward(pair[0], pos as i32, &config, &mut state, &weights);
+            let mut probs = state.logits.clone();
+            softmax(&mut probs);
+            nll.push(-probs[pair[1] as usize].ln());
+        }
+        assert!(nll.iter().all(|v| v.is_finite()));
+        let mean = |xs: &[f32]| xs.iter().sum::<f32>() / xs.len() as f32;
+        let inside = mean(&nll[seq_len / 2..seq_len]);
+        let beyond = mean(&nll[nll.len() - seq_len / 2..]);
+        assert!(
+            (beyond - inside).abs() < 0.1 * inside,
+            "nll {inside} within seq_len, {beyond} past it"
+        );
+    }
+
+    #[test]
+    fn streaming_overwrites_the_oldest_window_slot_in_place() {
+        let config = config(1);
+        let weights = LlamaWeights::random(&config, 4);
+        let kv_dim = config.kv_dim();
+        let policy = CachePolicy::Streaming {
+            n_sink: 2,
+            window: 6,
+        };
+        let tokens: Vec<i32> = (0..11).map(|i| (i * 3 + 1) % 32).collect();
+        let filled = run(&config, &weights, policy, &tokens[..8]);
+        let wrapped = run(&config, &weights, policy, &tokens);
+
+        // Three tokens past capacity took over window slots 0..3 in turn;
+        // the rest of the cache was not moved
+        assert_eq!(wrapped.ring_start, 3);
+        assert_eq!(wrapped.cache_len, 8);
+        let rows = |state: &LlamaState, slots: std::ops::Range<usize>| {
+            state.key_cache[0][slots.start * kv_dim..slots.end * kv_dim].to_vec()
+        };
+        assert_eq!(rows(&filled, 0..2), rows(&wrapped, 0..2));
+        assert_eq!(rows(&filled, 5..8), rows(&wrapped, 5..8));
+        let positions: Vec<usize> = (0..8).map(|t| wrapped.position(t)).collect();
+        assert_eq!(positions, [0, 1, 5, 6, 7, 2, 3, 4]);
+    }
+}
@@ -16,6 +16,7 @@ pub fn rms_norm(dest: &mut [f32], src: &[f32], weight: &[f32]) {
 
 /// Matrix-vector multiplication: xout = x @ w.T (w is row-major flattened).
 #[inline]
+#[allow(clippy::needless_range_loop)]
 pub fn matmul(xout: &mut [f32], x: &[f32], w: &[f32]) {
     let in_dim = x.len();
     let out_dim = xout.len();
@@ -54,6 +55,22 @@ pub fn softmax(x: &mut [f32]) {
     }
 }
 
+/// Log of the sum of exponentials, shifted by the maximum for stability.
+#[inline]
+pub fn log_sum_exp(x: &[f32]) -> f32 {
+    let max = x.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    max + x.iter().map(|&v| (v - max).exp()).sum::<f32>().ln()
+}
+
+/// Log-softmax in-place.
+#[inline]
+pub fn log_softmax(x: &mut [f32]) {
+    let log_sum = log_sum_exp(x);
+    for xi in x.iter_mut() {
+        *xi -= log_sum;
+    }
+}
+
 /// Apply rotary positional embeddings, aligned with apply_rotary_pos_emb.
 #[inline]
 pub fn apply_rotary_emb(x: &mut [f32], pos: i32, head_size: usize) {
@@ -74,6 +91,21 @@ pub fn apply_rotary_emb(x: &mut [f32], pos: i32, head_size: usize) {
     }
 }
 
+/// RoPE rotations for positions `0..n_pos`, as `(cos, sin)` pairs laid out
+/// `[n_pos][head_size / 2]`, matching [`apply_rotary_emb`].
+pub fn rope_table(n_pos: usize, head_size: usize) -> Vec<(f32, f32)> {
+    let head_size_f = head_size as f32;
+    let mut table = Vec::with_capacity(n_pos * head_size / 2);
+    for pos in 0..n_pos {
+        for head_dim in (0..head_size).step_by(2) {
+            let freq = 1.0 / (10000.0f32.powf(head_dim as f32 / head_size_f));
+            let (sin, cos) = (pos as f32 * freq).sin_cos();
+            table.push((cos, sin));
+        }
+    }
+    table
+}
+
 /// SwiGLU activation: gate * sigmoid(gate) * up
 #[inline]
 pub fn swiglu(gate: &mut [f32], up: &[f32]) {
@@ -82,3 +114,22 @@ pub fn swiglu(gate: &mut [f32], up: &[f32]) {
         *g = *g * sigmoid * u;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_softmax_matches_softmax() {
+        let logits = [2.0, -1.0, 0.5, f32::NEG_INFINITY, 1000.0];
+        let mut probs = logits;
+        softmax(&mut probs);
+        let mut logprobs = logits;
+        log_softmax(&mut logprobs);
+        for (&p, &lp) in probs.iter().zip(&logprobs) {
+            assert!((p - lp.exp()).abs() < 1e-6, "{p} vs {lp}");
+        }
+        assert_eq!(logprobs[3], f32::NEG_INFINITY);
+        assert!((log_sum_exp(&[0.0, 0.0]) - 2f32.ln()).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,741 @@
+//! Regex-constrained generation through a token-level DFA.
+//!
+//! The pattern is compiled into a byte-level DFA (UTF-8 classes are expanded
+//! into byte sequences), states that can no longer reach a match are pruned,
+//! and for every DFA state the vocabulary entries that keep the match alive are
+//! precomputed together with the state they lead to. Masking a step is then a
+//! table lookup, with no per-token string scanning.
+//!
+//! Supported syntax: literals, `.`, classes (`[a-z]`, `[^0-9]`), the escapes
+//! `\d \w \s \D \W \S` and escaped metacharacters, groups `(...)` / `(?:...)`,
+//! alternation `|`, and the quantifiers `* + ? {m} {m,} {m,n}`. The whole
+//! output must match; `^` and `$` at the ends of the pattern are accepted.
+
+use crate::constraint::Constraint;
+use crate::error::{LlamaError, Result};
+use crate::tokenizer::Tokenize;
+use std::collections::{BTreeSet, HashMap};
+
+/// Upper bound on DFA states, to fail fast on patterns that explode.
+pub const MAX_DFA_STATES: usize = 10_000;
+
+const DEAD: u32 = u32::MAX;
+
+/// Regex syntax tree.
+#[derive(Debug, Clone)]
+enum Node {
+    Empty,
+    /// One character in any of the inclusive ranges
+    Class(Vec<(char, char)>),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+/// A byte-level DFA for a regex.
+#[derive(Debug, Clone)]
+pub struct Dfa {
+    /// Transition table per state; `u32::MAX` is the dead state
+    pub next: Vec<[u32; 256]>,
+    pub accepting: Vec<bool>,
+}
+
+impl Dfa {
+    /// Compile a pattern into a DFA.
+    pub fn compile(pattern: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+        };
+        if parser.peek() == Some('^') {
+            parser.pos += 1;
+        }
+        let node = parser.parse_alt()?;
+        if parser.peek() == Some('$') && parser.pos + 1 == parser.chars.len() {
+            parser.pos += 1;
+        }
+        if let Some(c) = parser.peek() {
+            return Err(parser.error(&format!("unexpected '{c}'")));
+        }
+
+        let mut nfa = Nfa { states: Vec::new() };
+        let accept = nfa.push(NState::Match);
+        let start = nfa.compile(&node, accept);
+        let mut dfa = nfa.determinize(start)?;
+        dfa.prune();
+        Ok(dfa)
+    }
+
+    /// Run `bytes` from `state`, returning the resulting state or `DEAD`.
+    #[inline]
+    pub fn walk(&self, mut state: u32, bytes: &[u8]) -> u32 {
+        for &b in bytes {
+            state = self.next[state as usize][b as usize];
+            if state == DEAD {
+                break;
+            }
+        }
+        state
+    }
+
+    /// Redirect transitions into states that cannot reach a match to `DEAD`.
+    fn prune(&mut self) {
+        let n = self.next.len();
+        let mut live = self.accepting.clone();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for s in 0..n {
+                if !live[s] && self.next[s].iter().any(|&t| t != DEAD && live[t as usize]) {
+                    live[s] = true;
+                    changed = true;
+                }
+            }
+        }
+        for row in self.next.iter_mut() {
+            for t in row.iter_mut() {
+                if *t != DEAD && !live[*t as usize] {
+                    *t = DEAD;
+                }
+            }
+        }
+    }
+}
+
+/// Thompson NFA state over bytes.
+#[derive(Debug, Clone)]
+enum NState {
+    Byte(u8, u8, usize),
+    Split(usize, usize),
+    Epsilon(usize),
+    Match,
+}
+
+struct Nfa {
+    states: Vec<NState>,
+}
+
+impl Nfa {
+    fn push(&mut self, s: NState) -> usize {
+        self.states.push(s);
+        self.states.len() - 1
+    }
+
+    /// Compile `node` so that it continues to `next`; returns the entry state.
+    fn compile(&mut self, node: &Node, next: usize) -> usize {
+        match node {
+            Node::Empty => next,
+            Node::Class(ranges) => {
+                let entry = self.push(NState::Epsilon(next));
+                let mut alts = Vec::new();
+                for &(lo, hi) in ranges {
+                    for seq in utf8_sequences(lo as u32, hi as u32) {
+                        let mut target = next;
+                        for &(b0, b1) in seq.iter().rev() {
+                            target = self.push(NState::Byte(b0, b1, target));
+                        }
+                        alts.push(target);
+                    }
+                }
+                self.states[entry] = self.split_all(&alts, next);
+                entry
+            }
+            Node::Concat(nodes) => {
+                let mut target = next;
+                for n in nodes.iter().rev() {
+                    target = self.compile(n, target);
+                }
+                target
+            }
+            Node::Alt(nodes) => {
+                let alts: Vec<usize> = nodes.iter().map(|n| self.compile(n, next)).collect();
+                let entry = self.push(NState::Epsilon(next));
+                self.states[entry] = self.split_all(&alts, next);
+                entry
+            }
+            Node::Repeat(inner, min, max) => {
+                // Optional or starred tail first, then the mandatory copies
+                let mut target = match max {
+                    None => {
+                        // loop: split(inner -> loop, next)
+                        let lp = self.push(NState::Epsilon(next));
+                        let body = self.compile(inner, lp);
+                        self.states[lp] = NState::Split(body, next);
+                        lp
+                    }
+                    Some(max) => {
+                        let mut t = next;
+                        for _ in *min..*max {
+                            let body = self.compile(inner, t);
+                            t = self.push(NState::Split(body, next));
+                        }
+                        t
+                    }
+                };
+                for _ in 0..*min {
+                    target = self.compile(inner, target);
+                }
+                target
+            }
+        }
+    }
+
+    /// Build a state branching to every target (or `fallback` if none).
+    fn split_all(&mut self, targets: &[usize], fallback: usize) -> NState {
+        match targets {
+            [] => NState::Byte(1, 0, fallback), // matches nothing
+            [t] => NState::Epsilon(*t),
+            [first, rest @ ..] => {
+                let mut acc = *first;
+                for &t in rest {
+                    acc = self.push(NState::Split(acc, t));
+                }
+                NState::Epsilon(acc)
+            }
+        }
+    }
+
+    fn closure(&self, seeds: impl IntoIterator<Item = usize>) -> BTreeSet<usize> {
+        let mut set = BTreeSet::new();
+        let mut todo: Vec<usize> = seeds.into_iter().collect();
+        while let Some(s) = todo.pop() {
+            if !set.insert(s) {
+                continue;
+            }
+            match self.states[s] {
+                NState::Split(a, b) => todo.extend([a, b]),
+                NState::Epsilon(a) => todo.push(a),
+                _ => {}
+            }
+        }
+        set
+    }
+
+    /// Subset construction.
+    fn determinize(&self, start: usize) -> Result<Dfa> {
+        let mut ids: HashMap<BTreeSet<usize>, u32> = HashMap::new();
+        let mut sets = vec![self.closure([start])];
+        ids.insert(sets[0].clone(), 0);
+        let mut dfa = Dfa {
+            next: Vec::new(),
+            accepting: Vec::new(),
+        };
+
+        let mut i = 0;
+        while i < sets.len() {
+            let set = sets[i].clone();
+            let mut row = [DEAD; 256];
+            for b in 0..=255u8 {
+                let targets = set.iter().filter_map(|&s| match self.states[s] {
+                    NState::Byte(lo, hi, t) if lo <= b && b <= hi => Some(t),
+                    _ => None,
+                });
+                let target = self.closure(targets);
+                if target.is_empty() {
+                    continue;
+                }
+                let id = match ids.get(&target) {
+                    Some(&id) => id,
+                    None => {
+                        if sets.len() >= MAX_DFA_STATES {
+                            return Err(regex_err("pattern needs too many DFA states"));
+                        }
+                        let id = sets.len() as u32;
+                        ids.insert(target.clone(), id);
+                        sets.push(target);
+                        id
+                    }
+                };
+                row[b as usize] = id;
+            }
+            dfa.next.push(row);
+            dfa.accepting
+                .push(set.iter().any(|&s| matches!(self.states[s], NState::Match)));
+            i += 1;
+        }
+        Ok(dfa)
+    }
+}
+
+/// Split a scalar value range into UTF-8 byte-range sequences.
+fn utf8_sequences(lo: u32, hi: u32) -> Vec<Vec<(u8, u8)>> {
+    let mut out = Vec::new();
+    // Same-length ranges, skipping surrogates
+    for (a, b) in [
+        (0, 0x7F),
+        (0x80, 0x7FF),
+        (0x800, 0xD7FF),
+        (0xE000, 0xFFFF),
+        (0x10000, 0x10FFFF),
+    ] {
+        let (l, h) = (lo.max(a), hi.min(b));
+        if l <= h {
+            split_utf8(l, h, &mut out);
+        }
+    }
+    out
+}
+
+fn split_utf8(lo: u32, hi: u32, out: &mut Vec<Vec<(u8, u8)>>) {
+    let n = char_len(lo);
+    for i in 1..n {
+        let m = (1u32 << (6 * i)) - 1;
+        if lo & !m != hi & !m {
+            if lo & m != 0 {
+                split_utf8(lo, lo | m, out);
+                split_utf8((lo | m) + 1, hi, out);
+                return;
+            }
+            if hi & m != m {
+                split_utf8(lo, (hi & !m) - 1, out);
+                split_utf8(hi & !m, hi, out);
+                return;
+            }
+        }
+    }
+    let (a, b) = (encode(lo), encode(hi));
+    out.push(a.iter().zip(b.iter()).map(|(&x, &y)| (x, y)).collect());
+}
+
+fn char_len(c: u32) -> usize {
+    match c {
+        0..=0x7F => 1,
+        0x80..=0x7FF => 2,
+        0x800..=0xFFFF => 3,
+        _ => 4,
+    }
+}
+
+fn encode(c: u32) -> Vec<u8> {
+    let mut buf = [0u8; 4];
+    char::from_u32(c)
+        .map(|c| c.encode_utf8(&mut buf).as_bytes().to_vec())
+        .unwrap_or_default()
+}
+
+fn regex_err(msg: &str) -> LlamaError {
+    LlamaError::Grammar(format!("regex: {msg}"))
+}
+
+/// Recursive-descent regex parser.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn parse_alt(&mut self) -> Result<Node> {
+        let mut alts = vec![self.parse_concat()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alts.push(self.parse_concat()?);
+        }
+        Ok(if alts.len() == 1 {
+            alts.pop().unwrap_or(Node::Empty)
+        } else {
+            Node::Alt(alts)
+        })
+    }
+
+    fn parse_concat(&mut self) -> Result<Node> {
+        let mut items = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' || (c == '$' && self.pos + 1 == self.chars.len()) {
+                break;
+            }
+            let atom = self.parse_atom()?;
+            items.push(self.parse_quantifier(atom)?);
+        }
+        Ok(match items.len() {
+            0 => Node::Empty,
+            1 => items.pop().unwrap_or(Node::Empty),
+            _ => Node::Concat(items),
+        })
+    }
+
+    fn parse_atom(&mut self) -> Result<Node> {
+        let c = self.next().ok_or_else(|| self.error("unexpected end"))?;
+        match c {
+            '(' => {
+                if self.chars[self.pos..].starts_with(&['?', ':']) {
+                    self.pos += 2;
+                }
+                let node = self.parse_alt()?;
+                if self.next() != Some(')') {
+                    return Err(self.error("missing ')'"));
+                }
+                Ok(node)
+            }
+            '[' => self.parse_class(),
+            '.' => Ok(Node::Class(vec![('\0', '\u{9}'), ('\u{B}', char::MAX)])),
+            '\\' => {
+                let e = self.next().ok_or_else(|| self.error("unexpected end"))?;
+                Ok(Node::Class(escape_class(e).unwrap_or_else(|| {
+                    let c = escape_char(e);
+                    vec![(c, c)]
+                })))
+            }
+            '*' | '+' | '?' => Err(self.error("quantifier without a preceding item")),
+            c => Ok(Node::Class(vec![(c, c)])),
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<Node> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self
+                .next()
+                .ok_or_else(|| self.error("unterminated class"))?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = if c == '\\' {
+                let e = self.next().ok_or_else(|| self.error("unexpected end"))?;
+                if let Some(class) = escape_class(e) {
+                    ranges.extend(class);
+                    continue;
+                }
+                escape_char(e)
+            } else {
+                c
+            };
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let mut hi = self.next().ok_or_else(|| self.error("unexpected end"))?;
+                if hi == '\\' {
+                    hi = escape_char(self.next().ok_or_else(|| self.error("unexpected end"))?);
+                }
+                if hi < lo {
+                    return Err(self.error("invalid class range"));
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        Ok(Node::Class(if negated { negate(ranges) } else { ranges }))
+    }
+
+    fn parse_quantifier(&mut self, atom: Node) -> Result<Node> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                let save = self.pos;
+                self.pos += 1;
+                match self.parse_bounds() {
+                    Some(bounds) => {
+                        self.pos -= 1;
+                        bounds
+                    }
+                    None => {
+                        // A literal '{'
+                        self.pos = save;
+                        return Ok(atom);
+                    }
+                }
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error("invalid repetition bounds"));
+        }
+        // Lazy/possessive suffixes do not change the matched language
+        if matches!(self.peek(), Some('?' | '+')) {
+            self.pos += 1;
+        }
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+
+    /// Parse `m}`, `m,}` or `m,n}` after a '{'.
+    fn parse_bounds(&mut self) -> Option<(usize, Option<usize>)> {
+        let min = self.parse_int()?;
+        let max = if self.peek() == Some(',') {
+            self.pos += 1;
+            if self.peek() == Some('}') {
+                None
+            } else {
+                Some(self.parse_int()?)
+            }
+        } else {
+            Some(min)
+        };
+        (self.next() == Some('}')).then_some((min, max))
+    }
+
+    fn parse_int(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        digits.parse().ok()
+    }
+
+    #[inline]
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    #[inline]
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn error(&self, msg: &str) -> LlamaError {
+        regex_err(&format!("{msg} at offset {}", self.pos))
+    }
+}
+
+fn escape_char(e: char) -> char {
+    match e {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        c => c,
+    }
+}
+
+fn escape_class(e: char) -> Option<Vec<(char, char)>> {
+    let digit = vec![('0', '9')];
+    let word = vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+    let space = vec![('\t', '\r'), (' ', ' ')];
+    Some(match e {
+        'd' => digit,
+        'w' => word,
+        's' => space,
+        'D' => negate(digit),
+        'W' => negate(word),
+        'S' => negate(space),
+        _ => return None,
+    })
+}
+
+/// Complement a set of character ranges.
+fn negate(mut ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    ranges.sort();
+    let mut out = Vec::new();
+    let mut next = 0u32;
+    for (lo, hi) in ranges {
+        if (lo as u32) > next {
+            out.extend(range(next, lo as u32 - 1));
+        }
+        next = next.max(hi as u32 + 1);
+    }
+    if next <= char::MAX as u32 {
+        out.extend(range(next, char::MAX as u32));
+    }
+    out
+}
+
+/// A char range from scalar bounds, clipped around the surrogate gap.
+fn range(lo: u32, hi: u32) -> Vec<(char, char)> {
+    let mut out = Vec::new();
+    for (a, b) in [(lo, hi.min(0xD7FF)), (lo.max(0xE000), hi)] {
+        if let (Some(a), Some(b)) = (char::from_u32(a), char::from_u32(b))
+            && a <= b
+        {
+            out.push((a, b));
+        }
+    }
+    out
+}
+
+/// Regex matcher that masks vocabulary entries which cannot continue a match.
+#[derive(Debug, Clone)]
+pub struct RegexConstraint {
+    pub dfa: Dfa,
+    /// Per DFA state: the tokens that keep the match alive and their target state
+    pub transitions: Vec<Vec<(i32, u32)>>,
+    state: u32,
+    eos_tokens: Vec<i32>,
+}
+
+impl RegexConstraint {
+    /// Compile `pattern` and precompute token transitions over the tokenizer's vocabulary.
+    ///
+    /// Fails if the pattern is invalid, can match nothing from its start over
+    /// this vocabulary, or an end-of-generation id is outside the vocabulary.
+    pub fn new(pattern: &str, tokenizer: &dyn Tokenize, eos_tokens: &[i32]) -> Result<Self> {
+        let vocab_size = tokenizer.vocab_size();
+        if let Some(id) = eos_tokens
+            .iter()
+            .find(|&&id| id < 0 || id as usize >= vocab_size)
+        {
+            return Err(regex_err(&format!(
+                "end-of-generation token {id} is out of range for a vocabulary of {vocab_size}"
+            )));
+        }
+        let dfa = Dfa::compile(pattern)?;
+        let pieces: Vec<Vec<u8>> = (0..vocab_size as i32)
+            .map(|id| tokenizer.piece_bytes(id).unwrap_or_default())
+            .collect();
+
+        let transitions = (0..dfa.next.len() as u32)
+            .map(|state| {
+                pieces
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, bytes)| !bytes.is_empty())
+                    .filter_map(|(id, bytes)| {
+                        let next = dfa.walk(state, bytes);
+                        (next != DEAD).then_some((id as i32, next))
+                    })
+                    .collect()
+            })
+            .collect::<Vec<Vec<_>>>();
+        if transitions[0].is_empty() && !dfa.accepting[0] {
+            return Err(regex_err(&format!(
+                "pattern {pattern:?} cannot match any text from this vocabulary"
+            )));
+        }
+
+        Ok(RegexConstraint {
+            dfa,
+            transitions,
+            state: 0,
+            eos_tokens: eos_tokens.to_vec(),
+        })
+    }
+}
+
+impl Constraint for RegexConstraint {
+    fn mask(&self, logits: &mut [f32]) {
+        let mut masked = vec![f32::NEG_INFINITY; logits.len()];
+        let eos = if self.is_complete() {
+            &self.eos_tokens[..]
+        } else {
+            &[]
+        };
+        let allowed = self.transitions[self.state as usize]
+            .iter()
+            .map(|&(id, _)| id);
+        for id in allowed.chain(eos.iter().copied()) {
+            if let Some(&logit) = logits.get(id as usize) {
+                masked[id as usize] = logit;
+            }
+        }
+        logits.copy_from_slice(&masked);
+    }
+
+    fn accept(&mut self, token: i32) -> Result<()> {
+        if self.eos_tokens.contains(&token) {
+            if self.is_complete() {
+                return Ok(());
+            }
+            return Err(regex_err("end of generation before the pattern matched"));
+        }
+        let allowed = &self.transitions[self.state as usize];
+        let idx = allowed
+            .binary_search_by_key(&token, |&(id, _)| id)
+            .map_err(|_| regex_err(&format!("token {token} rejected by pattern")))?;
+        self.state = allowed[idx].1;
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.dfa.accepting[self.state as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::llama2_tokenizer;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        let dfa = Dfa::compile(pattern).unwrap();
+        let state = dfa.walk(0, text.as_bytes());
+        state != DEAD && dfa.accepting[state as usize]
+    }
+
+    #[test]
+    fn dfa_matches_whole_input() {
+        assert!(matches(r"\d{2,3}-[a-c]+", "123-abca"));
+        assert!(!matches(r"\d{2,3}-[a-c]+", "1-a"));
+        assert!(!matches(r"\d{2,3}-[a-c]+", "12-ad"));
+        assert!(matches("^(yes|no)$", "no"));
+        assert!(matches("[^a]é?", "bé"));
+        assert!(!matches("[^a]é?", "a"));
+    }
+
+    #[test]
+    fn constraint_masks_tokens_and_eos() {
+        let tok = llama2_tokenizer();
+        let eos = tok.eos_id;
+        let mut c = RegexConstraint::new("[0-9]+", &tok, &[eos]).unwrap();
+
+        let mut logits = vec![0.0; tok.vocab_size()];
+        c.mask(&mut logits);
+        let one = tok.token_id("1").unwrap();
+        assert_eq!(logits[one as usize], 0.0);
+        assert_eq!(
+            logits[tok.token_id("a").unwrap() as usize],
+            f32::NEG_INFINITY
+        );
+        assert_eq!(logits[eos as usize], f32::NEG_INFINITY);
+        assert!(c.accept(eos).is_err());
+
+        c.accept(one).unwrap();
+        let mut logits = vec![0.0; tok.vocab_size()];
+        c.mask(&mut logits);
+        assert_eq!(logits[eos as usize], 0.0);
+        c.accept(eos).unwrap();
+    }
+
+    #[test]
+    fn mask_ignores_ids_past_the_logits() {
+        let tok = llama2_tokenizer();
+        let c = RegexConstraint::new("[0-9]+", &tok, &[tok.eos_id]).unwrap();
+        let mut logits = vec![0.0; 100];
+        c.mask(&mut logits);
+        assert!(logits.contains(&0.0));
+    }
+
+    #[test]
+    fn rejects_out_of_range_eos() {
+        let tok = llama2_tokenizer();
+        assert!(RegexConstraint::new("a", &tok, &[32000]).is_err());
+        assert!(RegexConstraint::new("a", &tok, &[-1]).is_err());
+    }
+
+    #[test]
+    fn rejects_patterns_that_cannot_start() {
+        let tok = llama2_tokenizer();
+        assert!(Dfa::compile(r"[^\s\S]").is_ok());
+        assert!(RegexConstraint::new(r"[^\s\S]", &tok, &[tok.eos_id]).is_err());
+        assert!(RegexConstraint::new(r"a[^\s\S]", &tok, &[tok.eos_id]).is_err());
+    }
+
+    #[test]
+    fn multibyte_chars_spelled_with_byte_tokens() {
+        let tok = llama2_tokenizer();
+        let eos = tok.eos_id;
+        let mut c = RegexConstraint::new("日+", &tok, &[eos]).unwrap();
+        let bytes: Vec<i32> = "日".bytes().map(|b| tok.byte_tokens[b as usize]).collect();
+        for (i, &id) in bytes.iter().enumerate() {
+            let mut logits = vec![0.0; tok.vocab_size()];
+            c.mask(&mut logits);
+            assert_eq!(logits[id as usize], 0.0, "byte {i}");
+            assert_eq!(logits[eos as usize], f32::NEG_INFINITY, "byte {i}");
+            c.accept(id).unwrap();
+        }
+        assert!(c.is_complete());
+
+        // A second character must not start with a continuation byte
+        assert!(c.accept(bytes[1]).is_err());
+        c.accept(bytes[0]).unwrap();
+        assert!(!c.is_complete());
+        assert!(c.accept(tok.token_id("a").unwrap()).is_err());
+    }
+}
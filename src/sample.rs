@@ -1,96 +1,1129 @@
-//! Token sampling with temperature and top-p.
+//! Token sampling through an ordered chain of logit processors.
 
-use crate::ops::softmax;
-use rand::Rng;
+use crate::error::{LlamaError, Result};
+use crate::ops::log_sum_exp;
+use crate::tokenizer::Tokenize;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
 
-/// Used for sorting probabilities in top-p sampling.
-#[derive(Clone, Copy)]
-pub struct ProbIndex {
+/// A candidate token with its logit and (normalized) probability.
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate {
+    pub id: i32,
+    pub logit: f32,
     pub prob: f32,
-    pub index: usize,
 }
 
-/// Sample a token from logits with temperature and top-p.
-///
-/// - `temp == 0`: greedy (argmax)
-/// - `topp <= 0 || topp >= 1`: standard multinomial sampling
-/// - otherwise: nucleus (top-p) sampling
-pub fn sample<R: Rng>(logits: &mut [f32], temp: f64, topp: f64, rng: &mut R) -> i32 {
-    // Greedy decoding
-    if temp == 0.0 {
-        return argmax(logits) as i32;
+/// The set of tokens still under consideration while sampling.
+#[derive(Debug, Clone)]
+pub struct Candidates {
+    pub data: Vec<Candidate>,
+    /// Whether `data` is sorted by descending logit
+    pub sorted: bool,
+}
+
+impl Candidates {
+    /// Build candidates covering the whole vocabulary.
+    pub fn from_logits(logits: &[f32]) -> Self {
+        let data = logits
+            .iter()
+            .enumerate()
+            .map(|(i, &logit)| Candidate {
+                id: i as i32,
+                logit,
+                prob: 0.0,
+            })
+            .collect();
+        Candidates {
+            data,
+            sorted: false,
+        }
     }
 
-    // Scale by temperature
-    let temp_f32 = temp as f32;
-    for l in logits.iter_mut() {
-        *l /= temp_f32;
+    /// Sort by descending logit, if not already sorted.
+    pub fn sort(&mut self) {
+        if !self.sorted {
+            self.data.sort_by(|a, b| b.logit.total_cmp(&a.logit));
+            self.sorted = true;
+        }
     }
-    softmax(logits);
 
-    let r: f32 = rng.random();
+    /// Sort, then recompute probabilities over the remaining candidates.
+    pub fn softmax(&mut self) {
+        self.sort();
+        let Some(max) = self.data.first().map(|c| c.logit) else {
+            return;
+        };
+        let mut sum = 0.0f32;
+        for c in self.data.iter_mut() {
+            c.prob = (c.logit - max).exp();
+            sum += c.prob;
+        }
+        for c in self.data.iter_mut() {
+            c.prob /= sum;
+        }
+    }
+
+    /// Keep the first `n` candidates (at least one).
+    pub fn truncate(&mut self, n: usize) {
+        self.data.truncate(n.max(1));
+    }
+}
 
-    // Standard multinomial sampling
-    if topp <= 0.0 || topp >= 1.0 {
-        let mut cdf = 0.0f32;
-        for (i, &p) in logits.iter().enumerate() {
-            cdf += p;
-            if r < cdf {
-                return i as i32;
+/// Repetition penalties over a window of recent tokens.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Penalties {
+    /// Number of recent tokens considered (0 = disabled)
+    pub last_n: usize,
+    /// llama.cpp-style repetition penalty (1 = off)
+    pub repeat: f32,
+    /// OpenAI-style penalty scaled by the occurrence count (0 = off)
+    pub frequency: f32,
+    /// OpenAI-style penalty for any occurrence (0 = off)
+    pub presence: f32,
+    /// Tokens never penalized, such as newline
+    pub exempt: Vec<i32>,
+}
+
+impl Penalties {
+    /// Ids of the newline token, which penalties skip unless `penalize_nl` is set.
+    pub fn newline_ids(tokenizer: &dyn Tokenize) -> Vec<i32> {
+        tokenizer
+            .token_id("<0x0A>")
+            .or(tokenizer.token_id("\n"))
+            .into_iter()
+            .collect()
+    }
+
+    /// Returns true if applying the penalties would change nothing.
+    pub fn is_noop(&self) -> bool {
+        self.last_n == 0 || (self.repeat == 1.0 && self.frequency == 0.0 && self.presence == 0.0)
+    }
+}
+
+/// DRY ("don't repeat yourself") penalty for tokens that would extend a repeated n-gram.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dry {
+    /// Penalty scale (0 = disabled)
+    pub multiplier: f32,
+    /// Exponential growth of the penalty with the match length
+    pub base: f32,
+    /// Longest repeated n-gram that goes unpenalized
+    pub allowed_length: usize,
+    /// Number of recent tokens searched for repeats (0 = whole history)
+    pub last_n: usize,
+    /// Tokens that end a repeated sequence, such as newline or quotes
+    pub breakers: HashSet<i32>,
+}
+
+/// Longest match considered by DRY, which bounds both work and penalty growth.
+pub const DRY_MAX_MATCH: usize = 64;
+
+impl Dry {
+    /// Default sequence breakers, as in llama.cpp.
+    pub const DEFAULT_BREAKERS: [&str; 4] = ["\n", ":", "\"", "*"];
+
+    /// Collect the ids of vocabulary entries whose text contains any of `breakers`.
+    pub fn breaker_ids<S: AsRef<str>>(tokenizer: &dyn Tokenize, breakers: &[S]) -> HashSet<i32> {
+        let mut ids = HashSet::new();
+        for id in 0..tokenizer.vocab_size() as i32 {
+            let bytes = tokenizer.piece_bytes(id).unwrap_or_default();
+            let text = String::from_utf8_lossy(&bytes);
+            if breakers.iter().any(|b| text.contains(b.as_ref())) {
+                ids.insert(id);
             }
         }
-        return (logits.len() - 1) as i32;
+        ids
     }
+}
+
+/// A logit processor in the sampling chain.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stage {
+    /// Penalize tokens seen in the recent history
+    Penalties(Penalties),
+    /// Penalize tokens that extend an n-gram already present in the history
+    Dry(Dry),
+    /// Exclude top choices: with probability `probability`, drop every token
+    /// above `threshold` except the least likely of them
+    Xtc { probability: f32, threshold: f32 },
+    /// Keep the `k` most likely tokens (0 = disabled)
+    TopK(usize),
+    /// Keep the smallest set whose cumulative probability exceeds `p`
+    TopP(f32),
+    /// Drop tokens less likely than `p` times the most likely one
+    MinP(f32),
+    /// Locally typical sampling: keep tokens whose surprise is closest to the entropy
+    Typical(f32),
+    /// Tail-free sampling: cut where the second derivative of the sorted probabilities flattens out
+    TailFree(f32),
+    /// Divide logits by the temperature (0 = keep only the argmax)
+    Temperature(f32),
+}
 
-    // Top-p (nucleus) sampling
-    let mut prob_index: Vec<ProbIndex> = logits
+impl Stage {
+    /// Apply this processor to the candidates, given the tokens seen so far.
+    pub fn apply<R: Rng>(&self, cands: &mut Candidates, history: &[i32], rng: &mut R) {
+        match *self {
+            Stage::Penalties(ref p) => penalties(cands, p, history),
+            Stage::Dry(ref d) => dry(cands, d, history),
+            Stage::Xtc {
+                probability,
+                threshold,
+            } => {
+                if rng.random::<f32>() < probability {
+                    xtc(cands, threshold);
+                }
+            }
+            Stage::TopK(k) => top_k(cands, k),
+            Stage::TopP(p) => top_p(cands, p),
+            Stage::MinP(p) => min_p(cands, p),
+            Stage::Typical(p) => typical(cands, p),
+            Stage::TailFree(z) => tail_free(cands, z),
+            Stage::Temperature(t) => temperature(cands, t),
+        }
+    }
+}
+
+/// Apply repetition, frequency and presence penalties over the last `p.last_n` tokens.
+pub fn penalties(cands: &mut Candidates, p: &Penalties, history: &[i32]) {
+    if p.is_noop() {
+        return;
+    }
+    let window = &history[history.len().saturating_sub(p.last_n)..];
+    let mut counts: HashMap<i32, usize> = HashMap::new();
+    for &token in window {
+        if !p.exempt.contains(&token) {
+            *counts.entry(token).or_default() += 1;
+        }
+    }
+    if counts.is_empty() {
+        return;
+    }
+
+    for c in cands.data.iter_mut() {
+        let Some(&count) = counts.get(&c.id) else {
+            continue;
+        };
+        if c.logit <= 0.0 {
+            c.logit *= p.repeat;
+        } else {
+            c.logit /= p.repeat;
+        }
+        c.logit -= count as f32 * p.frequency + p.presence;
+    }
+    cands.sorted = false;
+}
+
+/// DRY sampler (Weidmann, 2024).
+///
+/// For every earlier occurrence of a token, measure how long the context
+/// preceding it matches the end of the history. Emitting that token again
+/// would extend the repeat, so it is penalized by
+/// `multiplier * base^(len - allowed_length)` once `len >= allowed_length`.
+pub fn dry(cands: &mut Candidates, d: &Dry, history: &[i32]) {
+    if d.multiplier == 0.0 || history.len() < 2 {
+        return;
+    }
+    let window = match d.last_n {
+        0 => history,
+        n => &history[history.len().saturating_sub(n)..],
+    };
+    let n = window.len();
+
+    // Longest match ending right before each position, keyed by the token there
+    let mut match_len: HashMap<i32, usize> = HashMap::new();
+    for i in 0..n - 1 {
+        let next = window[i + 1];
+        if d.breakers.contains(&next) {
+            continue;
+        }
+        let mut len = 0;
+        while len < DRY_MAX_MATCH && len <= i {
+            let a = window[i - len];
+            if a != window[n - 1 - len] || d.breakers.contains(&a) {
+                break;
+            }
+            len += 1;
+        }
+        if len >= d.allowed_length {
+            let best = match_len.entry(next).or_default();
+            *best = (*best).max(len);
+        }
+    }
+    if match_len.is_empty() {
+        return;
+    }
+
+    for c in cands.data.iter_mut() {
+        if let Some(&len) = match_len.get(&c.id) {
+            c.logit -= d.multiplier * d.base.powi((len - d.allowed_length) as i32);
+        }
+    }
+    cands.sorted = false;
+}
+
+/// XTC ("exclude top choices"): drop every token with probability at least
+/// `threshold` except the least likely of them, if there are two or more.
+pub fn xtc(cands: &mut Candidates, threshold: f32) {
+    if threshold > 0.5 || cands.data.len() < 2 {
+        return;
+    }
+    cands.softmax();
+    let above = cands
+        .data
+        .iter()
+        .take_while(|c| c.prob >= threshold)
+        .count();
+    if above >= 2 {
+        cands.data.drain(..above - 1);
+    }
+}
+
+/// Keep the `k` highest-logit candidates.
+pub fn top_k(cands: &mut Candidates, k: usize) {
+    if k == 0 || k >= cands.data.len() {
+        return;
+    }
+    cands.sort();
+    cands.truncate(k);
+}
+
+/// Nucleus sampling: keep the smallest prefix whose probability mass exceeds `p`.
+pub fn top_p(cands: &mut Candidates, p: f32) {
+    if p <= 0.0 || p >= 1.0 {
+        return;
+    }
+    cands.softmax();
+    let mut cum_prob = 0.0f32;
+    let mut keep = cands.data.len();
+    for (i, c) in cands.data.iter().enumerate() {
+        cum_prob += c.prob;
+        if cum_prob > p {
+            keep = i + 1;
+            break;
+        }
+    }
+    cands.truncate(keep);
+}
+
+/// Keep candidates with probability at least `p` times the maximum probability.
+pub fn min_p(cands: &mut Candidates, p: f32) {
+    if p <= 0.0 {
+        return;
+    }
+    cands.softmax();
+    let threshold = cands.data[0].prob * p;
+    let keep = cands
+        .data
+        .iter()
+        .take_while(|c| c.prob >= threshold)
+        .count();
+    cands.truncate(keep);
+}
+
+/// Locally typical sampling (Meister et al., 2022).
+pub fn typical(cands: &mut Candidates, p: f32) {
+    if p >= 1.0 || cands.data.len() <= 1 {
+        return;
+    }
+    cands.softmax();
+
+    // Entropy of the distribution
+    let entropy: f32 = cands
+        .data
+        .iter()
+        .filter(|c| c.prob > 0.0)
+        .map(|c| -c.prob * c.prob.ln())
+        .sum();
+
+    // Order by distance between each token's surprise and the entropy
+    let mut order: Vec<(f32, usize)> = cands
+        .data
         .iter()
         .enumerate()
-        .map(|(i, &p)| ProbIndex { prob: p, index: i })
+        .map(|(i, c)| ((-c.prob.ln() - entropy).abs(), i))
         .collect();
+    order.sort_by(|a, b| a.0.total_cmp(&b.0));
 
-    // Sort descending by probability
-    prob_index.sort_by(|a, b| {
-        b.prob
-            .partial_cmp(&a.prob)
-            .unwrap_or(std::cmp::Ordering::Equal)
-    });
-
-    // Find cutoff
-    let topp_f32 = topp as f32;
     let mut cum_prob = 0.0f32;
-    let mut last_idx = prob_index.len() - 1;
-    for (i, pi) in prob_index.iter().enumerate() {
-        cum_prob += pi.prob;
-        if cum_prob > topp_f32 {
-            last_idx = i;
+    let mut keep = order.len();
+    for (i, &(_, idx)) in order.iter().enumerate() {
+        cum_prob += cands.data[idx].prob;
+        if cum_prob > p {
+            keep = i + 1;
+            break;
+        }
+    }
+
+    let data = order[..keep.max(1)]
+        .iter()
+        .map(|&(_, idx)| cands.data[idx])
+        .collect();
+    cands.data = data;
+    cands.sorted = false;
+}
+
+/// Tail-free sampling (Phénix, 2019).
+pub fn tail_free(cands: &mut Candidates, z: f32) {
+    if z >= 1.0 || cands.data.len() <= 2 {
+        return;
+    }
+    cands.softmax();
+
+    // Absolute second derivative of the sorted probabilities, normalized
+    let first: Vec<f32> = cands
+        .data
+        .windows(2)
+        .map(|w| w[0].prob - w[1].prob)
+        .collect();
+    let mut second: Vec<f32> = first.windows(2).map(|w| (w[0] - w[1]).abs()).collect();
+    let sum: f32 = second.iter().sum();
+    if sum > 0.0 {
+        for d in second.iter_mut() {
+            *d /= sum;
+        }
+    } else {
+        let n = second.len() as f32;
+        second.fill(1.0 / n);
+    }
+
+    let mut cum = 0.0f32;
+    let mut keep = cands.data.len();
+    for (i, &d) in second.iter().enumerate() {
+        cum += d;
+        if cum > z {
+            keep = i + 1;
             break;
         }
     }
+    cands.truncate(keep);
+}
+
+/// Scale logits by `1 / t`; a non-positive temperature keeps only the argmax.
+pub fn temperature(cands: &mut Candidates, t: f32) {
+    if t <= 0.0 {
+        cands.sort();
+        cands.truncate(1);
+        return;
+    }
+    for c in cands.data.iter_mut() {
+        c.logit /= t;
+    }
+}
+
+/// Mirostat variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MirostatVersion {
+    V1,
+    V2,
+}
+
+/// Mirostat adaptive sampling (Basu et al., 2021).
+///
+/// Truncates the distribution so that the surprise of sampled tokens tracks
+/// the target `tau`, adjusting the threshold `mu` after every draw.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mirostat {
+    pub version: MirostatVersion,
+    /// Target surprise in bits
+    pub tau: f32,
+    /// Learning rate for `mu`
+    pub eta: f32,
+    /// Current maximum surprise, initialized to `2 * tau`
+    pub mu: f32,
+}
+
+/// Number of top tokens Mirostat v1 uses to estimate the Zipf exponent.
+pub const MIROSTAT_M: usize = 100;
+
+impl Mirostat {
+    /// Create a Mirostat state with `mu = 2 * tau`.
+    pub fn new(version: MirostatVersion, tau: f32, eta: f32) -> Self {
+        Mirostat {
+            version,
+            tau,
+            eta,
+            mu: 2.0 * tau,
+        }
+    }
+
+    /// Truncate the candidates, draw a token and update `mu`.
+    pub fn draw<R: Rng>(&mut self, cands: &mut Candidates, rng: &mut R) -> i32 {
+        cands.softmax();
+        match self.version {
+            MirostatVersion::V1 => {
+                // Estimate the Zipf exponent from the top tokens
+                let n_vocab = cands.data.len();
+                let m = MIROSTAT_M.min(n_vocab);
+                let mut sum_ti_bi = 0.0f32;
+                let mut sum_ti_sq = 0.0f32;
+                for i in 0..m.saturating_sub(1) {
+                    let t_i = ((i + 2) as f32 / (i + 1) as f32).ln();
+                    let b_i = (cands.data[i].prob / cands.data[i + 1].prob).ln();
+                    sum_ti_bi += t_i * b_i;
+                    sum_ti_sq += t_i * t_i;
+                }
+                let s_hat = sum_ti_bi / sum_ti_sq;
+
+                // Pick k so that the expected surprise matches mu
+                let eps_hat = s_hat - 1.0;
+                let k = ((eps_hat * 2f32.powf(self.mu)) / (1.0 - (n_vocab as f32).powf(-eps_hat)))
+                    .powf(1.0 / s_hat);
+                if k.is_finite() {
+                    top_k(cands, (k as usize).max(1));
+                }
+            }
+            MirostatVersion::V2 => {
+                let keep = cands
+                    .data
+                    .iter()
+                    .take_while(|c| -c.prob.log2() <= self.mu)
+                    .count();
+                cands.truncate(keep);
+            }
+        }
+
+        let token = draw(cands, rng);
+        let prob = cands
+            .data
+            .iter()
+            .find(|c| c.id == token)
+            .map_or(1.0, |c| c.prob);
+        let surprise = -prob.log2();
+        self.mu -= self.eta * (surprise - self.tau);
+        token
+    }
+}
 
-    // Sample from truncated distribution
-    let r_scaled = r * cum_prob;
+/// Draw a token from the candidates according to their probabilities.
+pub fn draw<R: Rng>(cands: &mut Candidates, rng: &mut R) -> i32 {
+    cands.softmax();
+    let r: f32 = rng.random();
     let mut cdf = 0.0f32;
-    for pi in prob_index.iter().take(last_idx + 1) {
-        cdf += pi.prob;
-        if r_scaled < cdf {
-            return pi.index as i32;
+    for c in &cands.data {
+        cdf += c.prob;
+        if r < cdf {
+            return c.id;
         }
     }
+    cands.data[cands.data.len() - 1].id
+}
+
+/// Log-probability of a chosen token and the most likely alternatives.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogprob {
+    pub id: i32,
+    pub logprob: f32,
+    /// Up to `n` most likely tokens with their log-probabilities, best first
+    pub top: Vec<(i32, f32)>,
+}
+
+/// Log-probabilities of `token` and the `n` most likely tokens under the
+/// unmodified model distribution, i.e. before classifier-free guidance,
+/// constraint masks, penalties and temperature.
+pub fn logprobs(logits: &[f32], token: i32, n: usize) -> TokenLogprob {
+    let log_sum = log_sum_exp(logits);
 
-    prob_index[last_idx].index as i32
+    let mut ids: Vec<usize> = (0..logits.len()).collect();
+    let n = n.min(ids.len());
+    if n > 0 {
+        ids.select_nth_unstable_by(n - 1, |&a, &b| logits[b].total_cmp(&logits[a]));
+        ids.truncate(n);
+        ids.sort_by(|&a, &b| logits[b].total_cmp(&logits[a]));
+    }
+    TokenLogprob {
+        id: token,
+        logprob: logits[token as usize] - log_sum,
+        top: ids[..n]
+            .iter()
+            .map(|&id| (id as i32, logits[id] - log_sum))
+            .collect(),
+    }
 }
 
-/// Returns the index of the maximum element.
-#[inline]
-fn argmax(x: &[f32]) -> usize {
-    let mut max_idx = 0;
-    let mut max_val = x[0];
-    for (i, &v) in x.iter().enumerate().skip(1) {
-        if v > max_val {
-            max_val = v;
-            max_idx = i;
+/// Sampler built from an ordered chain of stages followed by a random draw.
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    /// Added to the logits before the chain runs (`-inf` bans a token)
+    pub logit_bias: HashMap<i32, f32>,
+    /// End-of-generation tokens, masked until `min_tokens` tokens were sampled
+    pub eos_tokens: Vec<i32>,
+    pub min_tokens: usize,
+    pub stages: Vec<Stage>,
+    /// Replaces the final draw when set
+    pub mirostat: Option<Mirostat>,
+    /// Tokens accepted so far (prompt and generated)
+    pub history: Vec<i32>,
+    n_sampled: usize,
+    rng: StdRng,
+}
+
+impl Sampler {
+    /// Create a sampler from explicit stages and an RNG seed.
+    pub fn new(stages: Vec<Stage>, seed: u64) -> Self {
+        Sampler {
+            logit_bias: HashMap::new(),
+            eos_tokens: vec![2],
+            min_tokens: 0,
+            stages,
+            mirostat: None,
+            history: Vec::new(),
+            n_sampled: 0,
+            rng: StdRng::seed_from_u64(seed),
         }
     }
-    max_idx
+
+    /// Create a sampler that always picks the most likely token.
+    pub fn greedy() -> Self {
+        Self::new(vec![Stage::Temperature(0.0)], 0)
+    }
+
+    /// Record a token in the history without sampling it (e.g. prompt tokens).
+    pub fn accept(&mut self, token: i32) {
+        self.history.push(token);
+    }
+
+    /// Run the chain over `logits`, draw a token and accept it.
+    ///
+    /// Fails if the bias, `min_tokens` and any mask applied to `logits` leave
+    /// no token with a finite logit.
+    pub fn sample(&mut self, logits: &[f32]) -> Result<i32> {
+        let mut cands = Candidates::from_logits(logits);
+        self.apply_bias(&mut cands);
+        for stage in &self.stages {
+            stage.apply(&mut cands, &self.history, &mut self.rng);
+        }
+        let token = self.draw(&mut cands)?;
+        self.accept(token);
+        self.n_sampled += 1;
+        Ok(token)
+    }
+
+    /// Apply the logit bias, and mask end-of-generation tokens before `min_tokens`.
+    pub fn apply_bias(&self, cands: &mut Candidates) {
+        let mask_eos = self.n_sampled < self.min_tokens;
+        if self.logit_bias.is_empty() && !mask_eos {
+            return;
+        }
+        for c in cands.data.iter_mut() {
+            if let Some(&bias) = self.logit_bias.get(&c.id) {
+                c.logit += bias;
+            }
+            if mask_eos && self.eos_tokens.contains(&c.id) {
+                c.logit = f32::NEG_INFINITY;
+            }
+        }
+        cands.sorted = false;
+    }
+
+    /// Draw a token from the processed candidates, which must include one
+    /// with a finite logit.
+    pub fn draw(&mut self, cands: &mut Candidates) -> Result<i32> {
+        let max = cands
+            .data
+            .iter()
+            .map(|c| c.logit)
+            .fold(f32::NEG_INFINITY, f32::max);
+        if !max.is_finite() {
+            return Err(LlamaError::Sampling(
+                "every token is banned or masked; nothing can be sampled".into(),
+            ));
+        }
+        Ok(match self.mirostat.as_mut() {
+            Some(m) => m.draw(cands, &mut self.rng),
+            None => draw(cands, &mut self.rng),
+        })
+    }
+}
+
+/// Names of the stages accepted in [`SamplerConfig::order`].
+pub const STAGE_NAMES: [&str; 9] = [
+    "penalties",
+    "dry",
+    "xtc",
+    "top_k",
+    "top_p",
+    "min_p",
+    "typical",
+    "tfs",
+    "temp",
+];
+
+/// Sampling parameters, as exposed on the command line.
+///
+/// Disabled stages (`top_k == 0`, `top_p >= 1`, `min_p <= 0`, `typical_p >= 1`,
+/// `tfs_z >= 1`, neutral penalties, `dry.multiplier == 0`, `xtc_probability <= 0`)
+/// are left out of the chain. With Mirostat enabled, only `penalties`, `dry` and
+/// `temp` run before the Mirostat draw; the other truncation stages are ignored.
+#[derive(Debug, Clone)]
+pub struct SamplerConfig {
+    /// Per-token logit bias (`-inf` bans a token)
+    pub logit_bias: HashMap<i32, f32>,
+    /// Minimum number of tokens to sample before an EOS token is allowed
+    pub min_tokens: usize,
+    pub eos_tokens: Vec<i32>,
+    pub penalties: Penalties,
+    /// Also penalize newline tokens; see [`build_for`](SamplerConfig::build_for)
+    pub penalize_nl: bool,
+    pub dry: Dry,
+    pub xtc_probability: f32,
+    pub xtc_threshold: f32,
+    pub temperature: f32,
+    pub top_k: usize,
+    pub top_p: f32,
+    pub min_p: f32,
+    pub typical_p: f32,
+    pub tfs_z: f32,
+    /// Mirostat version (0 = off, 1 or 2)
+    pub mirostat: u8,
+    pub mirostat_tau: f32,
+    pub mirostat_eta: f32,
+    /// Stage order by name: `penalties`, `dry`, `top_k`, `top_p`, `min_p`,
+    /// `typical`, `tfs`, `xtc`, `temp`
+    pub order: Vec<String>,
+    pub seed: u64,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        SamplerConfig {
+            logit_bias: HashMap::new(),
+            min_tokens: 0,
+            eos_tokens: vec![2],
+            penalties: Penalties {
+                last_n: 64,
+                repeat: 1.0,
+                frequency: 0.0,
+                presence: 0.0,
+                exempt: Vec::new(),
+            },
+            penalize_nl: false,
+            dry: Dry {
+                multiplier: 0.0,
+                base: 1.75,
+                allowed_length: 2,
+                last_n: 0,
+                breakers: HashSet::new(),
+            },
+            xtc_probability: 0.0,
+            xtc_threshold: 0.1,
+            temperature: 1.0,
+            top_k: 0,
+            top_p: 0.9,
+            min_p: 0.0,
+            typical_p: 1.0,
+            tfs_z: 1.0,
+            mirostat: 0,
+            mirostat_tau: 5.0,
+            mirostat_eta: 0.1,
+            order: [
+                "penalties",
+                "dry",
+                "temp",
+                "top_k",
+                "tfs",
+                "typical",
+                "top_p",
+                "min_p",
+                "xtc",
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+            seed: 0,
+        }
+    }
+}
+
+impl SamplerConfig {
+    /// Parse a comma-separated stage order, e.g. `"top_k,top_p,temp"`.
+    pub fn set_order(&mut self, order: &str) {
+        self.order = order
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+    }
+
+    /// Build the sampler chain for `tokenizer`'s vocabulary, exempting the
+    /// newline token from penalties unless `penalize_nl` is set.
+    pub fn build_for(&self, tokenizer: &dyn Tokenize) -> Result<Sampler> {
+        if self.penalize_nl {
+            return self.build();
+        }
+        let mut config = self.clone();
+        config
+            .penalties
+            .exempt
+            .extend(Penalties::newline_ids(tokenizer));
+        config.build()
+    }
+
+    /// Build the sampler chain.
+    ///
+    /// Fails if a stage that is enabled with non-default settings is missing
+    /// from `order`, since it would silently have no effect.
+    pub fn build(&self) -> Result<Sampler> {
+        let mirostat = match self.mirostat {
+            0 => None,
+            1 => Some(MirostatVersion::V1),
+            2 => Some(MirostatVersion::V2),
+            v => return Err(LlamaError::Config(format!("unknown mirostat version: {v}"))),
+        };
+
+        let defaults = SamplerConfig::default();
+        for name in STAGE_NAMES {
+            let enabled = match name {
+                "penalties" => !self.penalties.is_noop(),
+                "dry" => self.dry.multiplier != 0.0,
+                "xtc" => self.xtc_probability > 0.0,
+                "top_k" => self.top_k > 0,
+                "top_p" => self.top_p < 1.0 && self.top_p != defaults.top_p,
+                "min_p" => self.min_p > 0.0,
+                "typical" => self.typical_p < 1.0,
+                "tfs" => self.tfs_z < 1.0,
+                "temp" => self.temperature != defaults.temperature,
+                _ => false,
+            };
+            let used = mirostat.is_none() || matches!(name, "penalties" | "dry" | "temp");
+            if enabled && used && !self.order.iter().any(|n| n == name) {
+                return Err(LlamaError::Config(format!(
+                    "sampler stage {name} is enabled but missing from the stage order"
+                )));
+            }
+        }
+
+        let mut stages = Vec::with_capacity(self.order.len());
+        for name in &self.order {
+            let stage = match name.as_str() {
+                "penalties" => {
+                    (!self.penalties.is_noop()).then(|| Stage::Penalties(self.penalties.clone()))
+                }
+                "dry" => (self.dry.multiplier != 0.0).then(|| Stage::Dry(self.dry.clone())),
+                "xtc" => (self.xtc_probability > 0.0).then_some(Stage::Xtc {
+                    probability: self.xtc_probability,
+                    threshold: self.xtc_threshold,
+                }),
+                "top_k" => (self.top_k > 0).then_some(Stage::TopK(self.top_k)),
+                "top_p" => (self.top_p < 1.0).then_some(Stage::TopP(self.top_p)),
+                "min_p" => (self.min_p > 0.0).then_some(Stage::MinP(self.min_p)),
+                "typical" => (self.typical_p < 1.0).then_some(Stage::Typical(self.typical_p)),
+                "tfs" => (self.tfs_z < 1.0).then_some(Stage::TailFree(self.tfs_z)),
+                "temp" => Some(Stage::Temperature(self.temperature)),
+                other => {
+                    return Err(LlamaError::Config(format!(
+                        "unknown sampler stage: {other}"
+                    )));
+                }
+            };
+            if mirostat.is_none() || matches!(name.as_str(), "penalties" | "dry" | "temp") {
+                stages.extend(stage);
+            }
+        }
+
+        let mut sampler = Sampler::new(stages, self.seed);
+        sampler.logit_bias = self.logit_bias.clone();
+        sampler.min_tokens = self.min_tokens;
+        sampler.eos_tokens = self.eos_tokens.clone();
+        sampler.mirostat = mirostat.map(|v| Mirostat::new(v, self.mirostat_tau, self.mirostat_eta));
+        Ok(sampler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Candidates whose logits are the logs of `probs`.
+    fn cands(probs: &[f32]) -> Candidates {
+        let logits: Vec<f32> = probs.iter().map(|p| p.ln()).collect();
+        Candidates::from_logits(&logits)
+    }
+
+    fn ids(cands: &Candidates) -> Vec<i32> {
+        cands.data.iter().map(|c| c.id).collect()
+    }
+
+    #[test]
+    fn penalties_apply_within_the_window() {
+        let p = Penalties {
+            last_n: 5,
+            repeat: 2.0,
+            frequency: 0.5,
+            presence: 1.0,
+            exempt: vec![4],
+        };
+        // Token 3 is outside the window and token 4 is exempt
+        let history = [3, 0, 1, 1, 2, 4];
+        let mut c = Candidates::from_logits(&[1.0, 2.0, -1.0, 3.0, 0.5]);
+        penalties(&mut c, &p, &history);
+        let logits: Vec<f32> = c.data.iter().map(|c| c.logit).collect();
+        // Positive logits are divided by the repeat penalty, negative ones
+        // multiplied, then frequency scales with the count
+        assert_eq!(
+            logits,
+            [1.0 / 2.0 - 1.5, 2.0 / 2.0 - 2.0, -2.0 - 1.5, 3.0, 0.5]
+        );
+
+        let off = Penalties { last_n: 0, ..p };
+        let mut c = Candidates::from_logits(&[1.0, 2.0]);
+        penalties(&mut c, &off, &history);
+        assert_eq!(c.data[1].logit, 2.0);
+    }
+
+    #[test]
+    fn xtc_cuts_top_choices_but_the_least_likely() {
+        let mut c = cands(&[0.4, 0.3, 0.2, 0.1]);
+        xtc(&mut c, 0.25);
+        assert_eq!(ids(&c), [1, 2, 3]);
+
+        let mut c = cands(&[0.1, 0.4, 0.3, 0.2]);
+        xtc(&mut c, 0.15);
+        assert_eq!(ids(&c), [3, 0]);
+    }
+
+    #[test]
+    fn xtc_needs_two_choices_above_threshold() {
+        let mut c = cands(&[0.4, 0.3, 0.2, 0.1]);
+        xtc(&mut c, 0.35);
+        assert_eq!(ids(&c), [0, 1, 2, 3]);
+
+        // Thresholds above 0.5 can never have two choices over them
+        let mut c = cands(&[0.4, 0.3, 0.2, 0.1]);
+        xtc(&mut c, 0.6);
+        assert_eq!(c.data.len(), 4);
+    }
+
+    #[test]
+    fn top_k_keeps_highest_logits() {
+        let mut c = Candidates::from_logits(&[1.0, 3.0, 2.0, 0.0]);
+        top_k(&mut c, 2);
+        assert_eq!(ids(&c), [1, 2]);
+    }
+
+    #[test]
+    fn top_p_keeps_smallest_prefix_above_p() {
+        let mut c = cands(&[0.15, 0.5, 0.05, 0.3]);
+        top_p(&mut c, 0.7);
+        assert_eq!(ids(&c), [1, 3]);
+
+        let mut c = cands(&[0.15, 0.5, 0.05, 0.3]);
+        top_p(&mut c, 0.85);
+        assert_eq!(ids(&c), [1, 3, 0]);
+    }
+
+    #[test]
+    fn min_p_scales_with_top_probability() {
+        let mut c = cands(&[0.5, 0.3, 0.15, 0.05]);
+        min_p(&mut c, 0.5);
+        assert_eq!(ids(&c), [0, 1]);
+    }
+
+    #[test]
+    fn typical_orders_by_distance_to_entropy() {
+        // Entropy is 1.14 nats; token 1 (surprise 1.20) is the most typical,
+        // then token 0 (0.69)
+        let mut c = cands(&[0.5, 0.3, 0.15, 0.05]);
+        typical(&mut c, 0.5);
+        assert_eq!(ids(&c), [1, 0]);
+    }
+
+    #[test]
+    fn tail_free_cuts_where_curvature_flattens() {
+        // Normalized second derivatives are 1/3, 2/3, 0
+        let mut c = cands(&[0.4, 0.3, 0.1, 0.1, 0.1]);
+        tail_free(&mut c, 0.5);
+        assert_eq!(ids(&c), [0, 1]);
+
+        let mut c = cands(&[0.4, 0.3, 0.1, 0.1, 0.1]);
+        tail_free(&mut c, 0.2);
+        assert_eq!(ids(&c), [0]);
+    }
+
+    #[test]
+    fn temperature_scales_logits() {
+        let mut c = Candidates::from_logits(&[1.0, 2.0]);
+        temperature(&mut c, 0.5);
+        let logits: Vec<f32> = c.data.iter().map(|c| c.logit).collect();
+        assert_eq!(logits, [2.0, 4.0]);
+
+        let mut c = Candidates::from_logits(&[1.0, 2.0, 0.5]);
+        temperature(&mut c, 0.0);
+        assert_eq!(ids(&c), [1]);
+    }
+
+    #[test]
+    fn dry_penalizes_tokens_extending_a_repeat() {
+        let mut d = Dry {
+            multiplier: 1.0,
+            base: 2.0,
+            allowed_length: 2,
+            last_n: 0,
+            breakers: HashSet::new(),
+        };
+        // "1 2 3 4 ... 1 2 3": emitting 4 would repeat a 3-token sequence
+        let history = [1, 2, 3, 4, 5, 1, 2, 3];
+        let mut c = Candidates::from_logits(&[0.0; 6]);
+        dry(&mut c, &d, &history);
+        let logits: Vec<f32> = c.data.iter().map(|c| c.logit).collect();
+        assert_eq!(logits, [0.0, 0.0, 0.0, 0.0, -2.0, 0.0]);
+
+        // A breaker inside the match stops it short of allowed_length
+        d.breakers.insert(2);
+        let mut c = Candidates::from_logits(&[0.0; 6]);
+        dry(&mut c, &d, &history);
+        assert!(c.data.iter().all(|c| c.logit == 0.0));
+    }
+
+    #[test]
+    fn mirostat_v2_truncates_by_surprise_and_updates_mu() {
+        let mut m = Mirostat::new(MirostatVersion::V2, 1.0, 0.1);
+        let mut rng = StdRng::seed_from_u64(0);
+        // Surprises are 1, 1.74 and 2.32 bits; mu = 2 keeps the first two
+        let mut c = cands(&[0.5, 0.3, 0.2]);
+        let token = m.draw(&mut c, &mut rng);
+        assert_eq!(ids(&c), [0, 1]);
+        let prob = if token == 0 { 0.625 } else { 0.375 };
+        let expected = 2.0 - 0.1 * (-f32::log2(prob) - 1.0);
+        assert!((m.mu - expected).abs() < 1e-5);
+    }
+
+    #[test]
+    fn mirostat_tracks_target_surprise() {
+        // Zipf distribution over 200 tokens
+        let probs: Vec<f32> = (1..=200).map(|r| (r as f32).powf(-1.3)).collect();
+        for version in [MirostatVersion::V1, MirostatVersion::V2] {
+            let mut m = Mirostat::new(version, 3.0, 0.1);
+            let mut rng = StdRng::seed_from_u64(1);
+            let mut total = 0.0;
+            let n = 2000;
+            for _ in 0..n {
+                let mut c = cands(&probs);
+                let token = m.draw(&mut c, &mut rng);
+                // Surprise under the truncated distribution the token was drawn from
+                let prob = c.data.iter().find(|c| c.id == token).unwrap().prob;
+                total += -prob.log2();
+            }
+            let mean = total / n as f32;
+            assert!(
+                (mean - 3.0).abs() < 0.5,
+                "{version:?}: mean surprise {mean}"
+            );
+        }
+    }
+
+    #[test]
+    fn build_rejects_enabled_stage_missing_from_order() {
+        let mut config = SamplerConfig {
+            dry: Dry {
+                multiplier: 0.8,
+                ..SamplerConfig::default().dry
+            },
+            ..Default::default()
+        };
+        config.set_order("top_k,temp");
+        assert!(config.build().is_err());
+        config.set_order("dry,top_k,temp");
+        assert!(config.build().is_ok());
+        // top_p at its default may be left out
+        config.set_order("dry,temp");
+        assert!(config.build().is_ok());
+    }
+
+    #[test]
+    fn logit_bias_shifts_and_bans_tokens() {
+        let config = SamplerConfig {
+            logit_bias: HashMap::from([(0, 5.0), (2, f32::NEG_INFINITY)]),
+            ..Default::default()
+        };
+        let sampler = config.build().unwrap();
+        let mut c = Candidates::from_logits(&[0.0, 3.0, 9.0]);
+        sampler.apply_bias(&mut c);
+        let logits: Vec<f32> = c.data.iter().map(|c| c.logit).collect();
+        assert_eq!(logits, [5.0, 3.0, f32::NEG_INFINITY]);
+
+        let mut sampler = config.build().unwrap();
+        for _ in 0..20 {
+            assert_ne!(sampler.sample(&[0.0, 3.0, 9.0]).unwrap(), 2);
+        }
+    }
+
+    #[test]
+    fn min_tokens_holds_back_eos() {
+        let mut sampler = SamplerConfig {
+            min_tokens: 3,
+            eos_tokens: vec![1],
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        let logits = [0.0, 20.0];
+        for _ in 0..3 {
+            assert_eq!(sampler.sample(&logits).unwrap(), 0);
+        }
+        assert_eq!(sampler.sample(&logits).unwrap(), 1);
+    }
+
+    #[test]
+    fn sampling_with_every_token_masked_fails() {
+        // A ban on the only token a constraint still allows
+        let mut sampler = SamplerConfig {
+            logit_bias: HashMap::from([(1, f32::NEG_INFINITY)]),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        let masked = [f32::NEG_INFINITY, 0.0, f32::NEG_INFINITY];
+        assert!(sampler.sample(&masked).is_err());
+
+        // min_tokens while only EOS is allowed
+        let mut sampler = SamplerConfig {
+            min_tokens: 1,
+            eos_tokens: vec![1],
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        assert!(sampler.sample(&masked).is_err());
+        assert!(sampler.history.is_empty());
+
+        let mut mirostat = SamplerConfig {
+            mirostat: 2,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        assert!(mirostat.sample(&[f32::NEG_INFINITY; 3]).is_err());
+    }
+
+    #[test]
+    fn chain_applies_stages_in_order() {
+        // top_k(2) then top_p(0.5) leaves only the best token; greedy picks it
+        let mut sampler = Sampler::new(vec![Stage::TopK(2), Stage::TopP(0.5)], 0);
+        let logits: Vec<f32> = [0.1f32, 0.6, 0.3].iter().map(|p| p.ln()).collect();
+        for _ in 0..10 {
+            assert_eq!(sampler.sample(&logits).unwrap(), 1);
+        }
+        assert_eq!(sampler.history.len(), 10);
+    }
+
+    #[test]
+    fn logprobs_report_chosen_token_and_top_alternatives_in_order() {
+        let logits = [1.0, 3.0, 2.0, 0.0, 2.5];
+        let log_sum = logits.iter().map(|l: &f32| l.exp()).sum::<f32>().ln();
+
+        let lp = logprobs(&logits, 3, 3);
+        assert_eq!(lp.id, 3);
+        assert!((lp.logprob - (0.0 - log_sum)).abs() < 1e-6);
+        let ids: Vec<i32> = lp.top.iter().map(|&(id, _)| id).collect();
+        assert_eq!(ids, [1, 4, 2]);
+        for &(id, l) in &lp.top {
+            assert!((l - (logits[id as usize] - log_sum)).abs() < 1e-6);
+        }
+
+        assert!(logprobs(&logits, 0, 0).top.is_empty());
+        assert_eq!(logprobs(&logits, 0, 10).top.len(), logits.len());
+    }
 }
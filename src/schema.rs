@@ -0,0 +1,438 @@
+//! JSON Schema to GBNF grammar conversion.
+//!
+//! Supports `type` (object, array, string, number, integer, boolean, null, or a
+//! list of these), `properties` and `required`, `items`, `minItems`/`maxItems`,
+//! `minLength`/`maxLength`, `enum`, `const`, and `anyOf`/`oneOf`. Objects are
+//! closed: properties are emitted in schema order and no others are allowed,
+//! so `additionalProperties` may only be `false`. Annotations such as `title`
+//! and `description` are ignored; every other keyword (`pattern`, `$ref`,
+//! `minimum`, `format`, ...) is rejected, since the grammar could not enforce it.
+
+use crate::error::{LlamaError, Result};
+use crate::grammar::Grammar;
+use crate::json::Json;
+use std::collections::HashSet;
+
+/// Parse a JSON Schema document and build a grammar for matching JSON values.
+pub fn json_schema_to_grammar(schema: &str) -> Result<Grammar> {
+    let gbnf = json_schema_to_gbnf(&Json::parse(schema)?)?;
+    Grammar::parse(&gbnf)
+}
+
+/// Convert a JSON Schema into GBNF. The root allows one leading space, which
+/// SentencePiece vocabularies attach to the first word.
+pub fn json_schema_to_gbnf(schema: &Json) -> Result<String> {
+    let mut conv = Converter {
+        rules: Vec::new(),
+        names: HashSet::new(),
+    };
+    let value = conv.visit(schema, "root-value")?;
+    conv.rules
+        .insert(0, ("root".into(), format!("\" \"? {value}")));
+
+    let mut out = String::new();
+    for (name, body) in &conv.rules {
+        out.push_str(&format!("{name} ::= {body}\n"));
+    }
+    Ok(out)
+}
+
+/// Keywords the converter enforces, or that do not constrain values.
+const KNOWN_KEYWORDS: [&str; 26] = [
+    "type",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "minLength",
+    "maxLength",
+    "enum",
+    "const",
+    "anyOf",
+    "oneOf",
+    // Annotations
+    "$schema",
+    "$id",
+    "$comment",
+    "$defs",
+    "definitions",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "deprecated",
+    "readOnly",
+    "writeOnly",
+    "nullable",
+];
+
+/// Built-in rules and the rules they depend on.
+const PRIMITIVES: [(&str, &str, &[&str]); 11] = [
+    ("ws", r#"| " " | "\n" [ \t]{0,20}"#, &[]),
+    (
+        "char",
+        r#"[^"\\\x7F\x00-\x1F] | [\\] (["\\bfnrt/] | "u" [0-9a-fA-F]{4})"#,
+        &[],
+    ),
+    ("string", r#""\"" char* "\"" ws"#, &["char", "ws"]),
+    ("integral-part", r#"[0] | [1-9] [0-9]{0,15}"#, &[]),
+    (
+        "number",
+        r#""-"? integral-part ("." [0-9]+)? ([eE] [-+]? [0-9]{1,3})? ws"#,
+        &["integral-part", "ws"],
+    ),
+    (
+        "integer",
+        r#""-"? integral-part ws"#,
+        &["integral-part", "ws"],
+    ),
+    ("boolean", r#"("true" | "false") ws"#, &["ws"]),
+    ("null", r#""null" ws"#, &["ws"]),
+    (
+        "value",
+        "object | array | string | number | boolean | null",
+        &["object", "array", "string", "number", "boolean", "null"],
+    ),
+    (
+        "object",
+        r#""{" ws (string ":" ws value ("," ws string ":" ws value)*)? "}" ws"#,
+        &["string", "value", "ws"],
+    ),
+    (
+        "array",
+        r#""[" ws (value ("," ws value)*)? "]" ws"#,
+        &["value", "ws"],
+    ),
+];
+
+struct Converter {
+    rules: Vec<(String, String)>,
+    names: HashSet<String>,
+}
+
+impl Converter {
+    /// Returns a GBNF expression matching `schema`, adding rules as needed.
+    fn visit(&mut self, schema: &Json, hint: &str) -> Result<String> {
+        let members = match schema {
+            Json::Bool(true) => return Ok(self.primitive("value")),
+            Json::Object(members) => members,
+            _ => return Err(schema_err("schema must be an object")),
+        };
+        for (key, value) in members {
+            if !KNOWN_KEYWORDS.contains(&key.as_str()) {
+                return Err(schema_err(&format!("unsupported keyword: {key}")));
+            }
+            if key == "additionalProperties" && *value != Json::Bool(false) {
+                return Err(schema_err("additionalProperties must be false"));
+            }
+        }
+        if members.is_empty() {
+            return Ok(self.primitive("value"));
+        }
+
+        if let Some(value) = schema.get("const") {
+            self.primitive("ws");
+            let body = format!("{} ws", literal(&value.to_string()));
+            return Ok(self.add_rule(hint, body));
+        }
+
+        if let Some(values) = schema.get("enum") {
+            let values = values
+                .as_array()
+                .ok_or_else(|| schema_err("enum must be an array"))?;
+            self.primitive("ws");
+            let alts: Vec<String> = values.iter().map(|v| literal(&v.to_string())).collect();
+            let body = format!("({}) ws", alts.join(" | "));
+            return Ok(self.add_rule(hint, body));
+        }
+
+        if let Some(options) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            let options = options
+                .as_array()
+                .ok_or_else(|| schema_err("anyOf/oneOf must be an array"))?;
+            let mut alts = Vec::with_capacity(options.len());
+            for (i, option) in options.iter().enumerate() {
+                alts.push(self.visit(option, &format!("{hint}-{i}"))?);
+            }
+            return Ok(self.add_rule(hint, alts.join(" | ")));
+        }
+
+        match schema.get("type") {
+            Some(Json::String(ty)) => self.visit_type(schema, ty, hint),
+            Some(Json::Array(types)) => {
+                let mut alts = Vec::with_capacity(types.len());
+                for ty in types {
+                    let ty = ty
+                        .as_str()
+                        .ok_or_else(|| schema_err("type must be a string"))?;
+                    alts.push(self.visit_type(schema, ty, &format!("{hint}-{ty}"))?);
+                }
+                Ok(self.add_rule(hint, alts.join(" | ")))
+            }
+            Some(_) => Err(schema_err("type must be a string or an array")),
+            None if schema.get("properties").is_some() => self.visit_type(schema, "object", hint),
+            None if schema.get("items").is_some() => self.visit_type(schema, "array", hint),
+            None => Ok(self.primitive("value")),
+        }
+    }
+
+    fn visit_type(&mut self, schema: &Json, ty: &str, hint: &str) -> Result<String> {
+        match ty {
+            "object" => self.visit_object(schema, hint),
+            "array" => self.visit_array(schema, hint),
+            "string" => {
+                let min = count(schema, "minLength")?;
+                let max = count(schema, "maxLength")?;
+                if min.is_none() && max.is_none() {
+                    return Ok(self.primitive("string"));
+                }
+                self.primitive("char");
+                self.primitive("ws");
+                let body = format!(r#""\"" char{} "\"" ws"#, repetition(min.unwrap_or(0), max));
+                Ok(self.add_rule(hint, body))
+            }
+            "number" | "integer" | "boolean" | "null" => Ok(self.primitive(ty)),
+            other => Err(schema_err(&format!("unknown type: {other}"))),
+        }
+    }
+
+    fn visit_object(&mut self, schema: &Json, hint: &str) -> Result<String> {
+        let Some(properties) = schema.get("properties") else {
+            return Ok(self.primitive("object"));
+        };
+        let properties = properties
+            .as_object()
+            .ok_or_else(|| schema_err("properties must be an object"))?;
+        let required: Vec<&str> = match schema.get("required") {
+            Some(list) => list
+                .as_array()
+                .ok_or_else(|| schema_err("required must be an array"))?
+                .iter()
+                .map(|v| {
+                    v.as_str()
+                        .ok_or_else(|| schema_err("required must list strings"))
+                })
+                .collect::<Result<_>>()?,
+            None => Vec::new(),
+        };
+        for name in &required {
+            if !properties.iter().any(|(k, _)| k == name) {
+                return Err(schema_err(&format!(
+                    "required property not defined: {name}"
+                )));
+            }
+        }
+        self.primitive("ws");
+
+        // One "key": value expression per property, in schema order
+        let mut props = Vec::with_capacity(properties.len());
+        for (key, prop) in properties {
+            let value = self.visit(prop, &format!("{hint}-{}", sanitize(key)))?;
+            let kv = format!(
+                "{} \":\" ws {value}",
+                literal(&Json::String(key.clone()).to_string())
+            );
+            props.push((kv, required.contains(&key.as_str())));
+        }
+
+        let body = format!("\"{{\" ws {} \"}}\" ws", first_property(&props));
+        Ok(self.add_rule(hint, body))
+    }
+
+    fn visit_array(&mut self, schema: &Json, hint: &str) -> Result<String> {
+        let item = match schema.get("items") {
+            Some(items) => self.visit(items, &format!("{hint}-item"))?,
+            None => self.primitive("value"),
+        };
+        let min = count(schema, "minItems")?.unwrap_or(0);
+        let max = count(schema, "maxItems")?;
+        self.primitive("ws");
+
+        let body = match (min, max) {
+            (_, Some(0)) => r#""[" ws "]" ws"#.to_string(),
+            (0, max) => format!(
+                r#""[" ws ({item} ("," ws {item}){})? "]" ws"#,
+                repetition(0, max.map(|m| m - 1))
+            ),
+            (min, max) => format!(
+                r#""[" ws {item} ("," ws {item}){} "]" ws"#,
+                repetition(min - 1, max.map(|m| m - 1))
+            ),
+        };
+        Ok(self.add_rule(hint, body))
+    }
+
+    /// Add a built-in rule and its dependencies; returns its name.
+    fn primitive(&mut self, name: &str) -> String {
+        if self.names.insert(name.to_string()) {
+            let (_, body, deps) = PRIMITIVES
+                .iter()
+                .find(|(n, _, _)| *n == name)
+                .copied()
+                .unwrap_or_default();
+            self.rules.push((name.to_string(), body.to_string()));
+            for dep in deps {
+                self.primitive(dep);
+            }
+        }
+        name.to_string()
+    }
+
+    /// Add a rule under a unique name derived from `hint`.
+    fn add_rule(&mut self, hint: &str, body: String) -> String {
+        let mut name = hint.to_string();
+        let mut n = 1;
+        while self.names.contains(&name) || PRIMITIVES.iter().any(|(p, _, _)| *p == name) {
+            name = format!("{hint}{n}");
+            n += 1;
+        }
+        self.names.insert(name.clone());
+        self.rules.push((name.clone(), body));
+        name
+    }
+}
+
+/// Properties from the first one present onwards, which carries no comma.
+/// Each optional property may be skipped until a required one is reached.
+fn first_property(props: &[(String, bool)]) -> String {
+    let Some(((kv, required), rest)) = props.split_first() else {
+        return String::new();
+    };
+    let present = format!("{kv}{}", later_properties(rest));
+    match (*required, first_property(rest)) {
+        (true, _) => present,
+        (false, skipped) if skipped.is_empty() => format!("({present})?"),
+        (false, skipped) => format!("({present} | {skipped})"),
+    }
+}
+
+/// Properties after one is already present, each preceded by a comma.
+fn later_properties(props: &[(String, bool)]) -> String {
+    props
+        .iter()
+        .map(|(kv, required)| match required {
+            true => format!(" \",\" ws {kv}"),
+            false => format!(" (\",\" ws {kv})?"),
+        })
+        .collect()
+}
+
+/// Read a non-negative integer keyword.
+fn count(schema: &Json, key: &str) -> Result<Option<usize>> {
+    match schema.get(key) {
+        None => Ok(None),
+        Some(v) => match v.as_f64() {
+            Some(n) if n >= 0.0 && n.fract() == 0.0 => Ok(Some(n as usize)),
+            _ => Err(schema_err(&format!("{key} must be a non-negative integer"))),
+        },
+    }
+}
+
+/// GBNF repetition suffix for `{min,max}`.
+fn repetition(min: usize, max: Option<usize>) -> String {
+    match max {
+        None if min == 0 => "*".into(),
+        None => format!("{{{min},}}"),
+        Some(max) => format!("{{{min},{max}}}"),
+    }
+}
+
+/// Quote text as a GBNF string literal.
+fn literal(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Turn a property name into a valid rule name fragment.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+fn schema_err(msg: &str) -> LlamaError {
+    LlamaError::Json(format!("JSON Schema: {msg}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn properties_follow_declaration_order() {
+        let g = json_schema_to_grammar(
+            r#"{"type": "object", "properties": {
+                "a": {"type": "integer"},
+                "b": {"type": "string"},
+                "c": {"type": "boolean"}
+            }, "required": ["b"]}"#,
+        )
+        .unwrap();
+        for ok in [
+            r#"{"b": "x"}"#,
+            r#"{"a": 1, "b": "x"}"#,
+            r#"{"b": "x", "c": true}"#,
+            r#" {"a": 1, "b": "x", "c": false}"#,
+        ] {
+            assert!(g.matches(ok), "{ok}");
+        }
+        for bad in [
+            r#"{}"#,
+            r#"{"a": 1}"#,
+            r#"{"b": "x", "a": 1}"#,
+            r#"{"b": "x", "d": 1}"#,
+            r#"{, "b": "x"}"#,
+        ] {
+            assert!(!g.matches(bad), "{bad}");
+        }
+    }
+
+    #[test]
+    fn all_optional_properties() {
+        let g =
+            json_schema_to_grammar(r#"{"properties": {"a": {"type": "null"}, "b": {"const": 1}}}"#)
+                .unwrap();
+        for ok in [
+            r#"{}"#,
+            r#"{"a": null}"#,
+            r#"{"b": 1}"#,
+            r#"{"a": null, "b": 1}"#,
+        ] {
+            assert!(g.matches(ok), "{ok}");
+        }
+        assert!(!g.matches(r#"{"b": 1, "a": null}"#));
+    }
+
+    #[test]
+    fn rejects_keywords_it_cannot_enforce() {
+        for schema in [
+            r#"{"type": "integer", "minimum": 0}"#,
+            r#"{"type": "string", "format": "date"}"#,
+            r#"{"type": "string", "pattern": "^a"}"#,
+            r##"{"$ref": "#/$defs/x"}"##,
+            r#"{"properties": {"a": {}}, "additionalProperties": true}"#,
+            r#"{"properties": {"a": {"type": "number", "maximum": 1}}}"#,
+        ] {
+            assert!(json_schema_to_grammar(schema).is_err(), "{schema}");
+        }
+        for schema in [
+            r#"{"title": "x", "description": "y", "type": "string"}"#,
+            r#"{"properties": {"a": {}}, "additionalProperties": false}"#,
+        ] {
+            assert!(json_schema_to_grammar(schema).is_ok(), "{schema}");
+        }
+    }
+}
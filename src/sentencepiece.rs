@@ -0,0 +1,378 @@
+//! SentencePiece `tokenizer.model` loading.
+//!
+//! Decodes just enough of the `ModelProto` protobuf to build a [`Tokenizer`]:
+//! the pieces with their scores and types, the trainer's model type and
+//! special ids, and the normalizer flags.
+
+use crate::error::{LlamaError, Result};
+use crate::tokenizer::{Tokenizer, byte_token};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// SentencePiece word-boundary marker, stored as a plain space in `Tokenizer` pieces.
+const METASPACE: char = '\u{2581}';
+
+/// `SentencePiece.Type` values.
+const NORMAL: u64 = 1;
+const UNKNOWN: u64 = 2;
+const CONTROL: u64 = 3;
+const USER_DEFINED: u64 = 4;
+const BYTE: u64 = 6;
+
+/// `TrainerSpec.ModelType` value for BPE.
+const MODEL_BPE: u64 = 2;
+
+/// Load a tokenizer from a SentencePiece `tokenizer.model` file.
+pub fn load_sentencepiece<P: AsRef<Path>>(path: P) -> Result<Tokenizer> {
+    parse_sentencepiece(&std::fs::read(path)?)
+}
+
+/// Build a tokenizer from a serialized SentencePiece `ModelProto`.
+///
+/// Only BPE models are supported. Control and unknown pieces become special
+/// tokens and byte pieces the byte fallback; pieces other than normal and
+/// user-defined ones get a score of `-inf`, so merging never produces them.
+pub fn parse_sentencepiece(data: &[u8]) -> Result<Tokenizer> {
+    let mut vocab = Vec::new();
+    let mut scores = Vec::new();
+    let mut types = Vec::new();
+    let mut trainer = TrainerSpec::default();
+    let mut normalizer = NormalizerSpec::default();
+
+    let mut fields = Fields(data);
+    while let Some((field, value)) = fields.next_field()? {
+        match (field, value) {
+            (1, Value::Bytes(piece)) => {
+                let (piece, score, ty) = parse_piece(piece)?;
+                vocab.push(piece);
+                scores.push(score);
+                types.push(ty);
+            }
+            (2, Value::Bytes(spec)) => trainer = TrainerSpec::parse(spec)?,
+            (3, Value::Bytes(spec)) => normalizer = NormalizerSpec::parse(spec)?,
+            _ => {}
+        }
+    }
+
+    if trainer.model_type != MODEL_BPE {
+        return Err(sp_err(&format!(
+            "unsupported model type {} (only BPE)",
+            trainer.model_type
+        )));
+    }
+    if normalizer.has_charsmap && normalizer.name != "identity" {
+        return Err(sp_err(&format!(
+            "unsupported normalizer: {}",
+            normalizer.name
+        )));
+    }
+
+    let mut special_tokens = HashMap::new();
+    let mut byte_tokens = vec![-1; 256];
+    let mut unk_id = None;
+    for (id, (piece, ty)) in vocab.iter_mut().zip(&types).enumerate() {
+        let id = id as i32;
+        match *ty {
+            NORMAL | USER_DEFINED => *piece = piece.replace(METASPACE, " "),
+            UNKNOWN => {
+                unk_id = Some(id);
+                special_tokens.insert(piece.clone(), id);
+            }
+            CONTROL => {
+                special_tokens.insert(piece.clone(), id);
+            }
+            BYTE => {
+                let byte = byte_token(piece)
+                    .ok_or_else(|| sp_err(&format!("invalid byte piece: {piece}")))?;
+                byte_tokens[byte as usize] = id;
+            }
+            _ => {}
+        }
+        if !matches!(*ty, NORMAL | USER_DEFINED) {
+            scores[id as usize] = f32::NEG_INFINITY;
+        }
+    }
+    let byte_tokens = if byte_tokens.iter().all(|&id| id < 0) {
+        Vec::new()
+    } else if byte_tokens.iter().any(|&id| id < 0) {
+        return Err(sp_err("incomplete byte pieces"));
+    } else {
+        byte_tokens
+    };
+
+    let mut vocab_map = HashMap::with_capacity(vocab.len());
+    for (id, piece) in vocab.iter().enumerate() {
+        vocab_map.insert(piece.clone(), id as i32);
+    }
+    let special = |id: i64, name: &str| -> Result<i32> {
+        match id {
+            id if id >= 0 && (id as usize) < vocab.len() => Ok(id as i32),
+            _ => Err(sp_err(&format!("{name} id {id} out of range"))),
+        }
+    };
+
+    Ok(Tokenizer {
+        max_token_len: vocab.iter().map(|p| p.len() as u32).max().unwrap_or(0),
+        bos_id: special(trainer.bos_id, "BOS")?,
+        eos_id: special(trainer.eos_id, "EOS")?,
+        vocab,
+        scores,
+        vocab_map,
+        merges: HashMap::new(),
+        special_tokens,
+        unk_id,
+        byte_tokens,
+        add_dummy_prefix: normalizer.add_dummy_prefix,
+        split_on_space: false,
+        remove_extra_whitespaces: normalizer.remove_extra_whitespaces,
+    })
+}
+
+/// Decode a `SentencePiece` message into (piece, score, type).
+fn parse_piece(data: &[u8]) -> Result<(String, f32, u64)> {
+    let (mut piece, mut score, mut ty) = (String::new(), 0.0, NORMAL);
+    let mut fields = Fields(data);
+    while let Some((field, value)) = fields.next_field()? {
+        match (field, value) {
+            (1, Value::Bytes(bytes)) => piece = String::from_utf8_lossy(bytes).into_owned(),
+            (2, Value::Fixed32(bits)) => score = f32::from_bits(bits),
+            (3, Value::Varint(v)) => ty = v,
+            _ => {}
+        }
+    }
+    Ok((piece, score, ty))
+}
+
+/// The `TrainerSpec` fields that affect encoding.
+struct TrainerSpec {
+    model_type: u64,
+    bos_id: i64,
+    eos_id: i64,
+}
+
+impl Default for TrainerSpec {
+    fn default() -> Self {
+        // Protobuf defaults: UNIGRAM, <s> = 1, </s> = 2
+        TrainerSpec {
+            model_type: 1,
+            bos_id: 1,
+            eos_id: 2,
+        }
+    }
+}
+
+impl TrainerSpec {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let mut spec = TrainerSpec::default();
+        let mut fields = Fields(data);
+        while let Some((field, value)) = fields.next_field()? {
+            match (field, value) {
+                (3, Value::Varint(v)) => spec.model_type = v,
+                // int32 fields are sign-extended to 64 bits on the wire
+                (41, Value::Varint(v)) => spec.bos_id = v as i64,
+                (42, Value::Varint(v)) => spec.eos_id = v as i64,
+                _ => {}
+            }
+        }
+        Ok(spec)
+    }
+}
+
+/// The `NormalizerSpec` fields that affect encoding.
+struct NormalizerSpec {
+    name: String,
+    has_charsmap: bool,
+    add_dummy_prefix: bool,
+    remove_extra_whitespaces: bool,
+}
+
+impl Default for NormalizerSpec {
+    fn default() -> Self {
+        NormalizerSpec {
+            name: String::new(),
+            has_charsmap: false,
+            add_dummy_prefix: true,
+            remove_extra_whitespaces: true,
+        }
+    }
+}
+
+impl NormalizerSpec {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let mut spec = NormalizerSpec::default();
+        let mut fields = Fields(data);
+        while let Some((field, value)) = fields.next_field()? {
+            match (field, value) {
+                (1, Value::Bytes(name)) => spec.name = String::from_utf8_lossy(name).into_owned(),
+                (2, Value::Bytes(map)) => spec.has_charsmap = !map.is_empty(),
+                (3, Value::Varint(v)) => spec.add_dummy_prefix = v != 0,
+                (4, Value::Varint(v)) => spec.remove_extra_whitespaces = v != 0,
+                _ => {}
+            }
+        }
+        Ok(spec)
+    }
+}
+
+/// A decoded protobuf field value.
+enum Value<'a> {
+    Varint(u64),
+    Fixed64,
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// Iterator over the fields of a protobuf message.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn next_field(&mut self) -> Result<Option<(u64, Value<'a>)>> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Value::Fixed64
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => Value::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            wire => return Err(sp_err(&format!("unsupported wire type {wire}"))),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.0.split_first().ok_or_else(|| sp_err("truncated"))?;
+            self.0 = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(sp_err("varint too long"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.0.len() {
+            return Err(sp_err("truncated"));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+}
+
+fn sp_err(msg: &str) -> LlamaError {
+    LlamaError::Tokenizer(format!("sentencepiece: {msg}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(out: &mut Vec<u8>, mut v: u64) {
+        while v >= 0x80 {
+            out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn varint_field(out: &mut Vec<u8>, field: u64, v: u64) {
+        varint(out, field << 3);
+        varint(out, v);
+    }
+
+    fn bytes_field(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+        varint(out, field << 3 | 2);
+        varint(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+
+    fn piece(text: &str, score: f32, ty: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        bytes_field(&mut out, 1, text.as_bytes());
+        varint(&mut out, 2 << 3 | 5);
+        out.extend_from_slice(&score.to_le_bytes());
+        varint_field(&mut out, 3, ty);
+        out
+    }
+
+    /// A `ModelProto` with `<unk>`, `</s>` and `<s>`, the given byte pieces,
+    /// then `▁`, `a`, `b`, `▁a` and `ab`; BOS is id 2 and EOS id 1.
+    fn model(model_type: u64, bytes: std::ops::Range<u32>) -> Vec<u8> {
+        let mut pieces = vec![
+            piece("<unk>", 0.0, UNKNOWN),
+            piece("</s>", 0.0, CONTROL),
+            piece("<s>", 0.0, CONTROL),
+        ];
+        pieces.extend(bytes.map(|b| piece(&format!("<0x{b:02X}>"), 0.0, BYTE)));
+        for (i, text) in ["▁", "a", "b", "▁a", "ab"].into_iter().enumerate() {
+            pieces.push(piece(text, -(i as f32), NORMAL));
+        }
+
+        let mut trainer = Vec::new();
+        varint_field(&mut trainer, 3, model_type);
+        varint_field(&mut trainer, 41, 2);
+        varint_field(&mut trainer, 42, 1);
+        let mut normalizer = Vec::new();
+        bytes_field(&mut normalizer, 1, b"identity");
+        varint_field(&mut normalizer, 3, 0);
+        varint_field(&mut normalizer, 4, 0);
+
+        let mut out = Vec::new();
+        for p in &pieces {
+            bytes_field(&mut out, 1, p);
+        }
+        bytes_field(&mut out, 2, &trainer);
+        bytes_field(&mut out, 3, &normalizer);
+        out
+    }
+
+    #[test]
+    fn parses_pieces_and_specs() {
+        let tok = parse_sentencepiece(&model(MODEL_BPE, 0..256)).unwrap();
+        assert_eq!(tok.vocab.len(), 3 + 256 + 5);
+        assert_eq!((tok.bos_id, tok.eos_id, tok.unk_id), (2, 1, Some(0)));
+        assert_eq!(tok.special_tokens.len(), 3);
+        assert_eq!(tok.special_tokens["<s>"], 2);
+        assert_eq!(tok.special_tokens["</s>"], 1);
+        assert_eq!(tok.special_tokens["<unk>"], 0);
+        assert_eq!(tok.byte_tokens, (3..259).collect::<Vec<i32>>());
+        assert!(!tok.add_dummy_prefix && !tok.remove_extra_whitespaces);
+
+        // Only normal pieces keep their scores; the metaspace becomes a space
+        for id in 0..259 {
+            assert_eq!(tok.scores[id], f32::NEG_INFINITY, "{}", tok.vocab[id]);
+        }
+        assert_eq!(tok.scores[259..], [0.0, -1.0, -2.0, -3.0, -4.0]);
+        assert_eq!(tok.vocab[262], " a");
+        assert_eq!(tok.vocab_map[" a"], 262);
+
+        assert_eq!(tok.encode("ab a", false, false).unwrap(), [263, 262]);
+        let ids = tok.encode("aé", false, false).unwrap();
+        assert_eq!(ids, [260, 3 + 0xC3, 3 + 0xA9]);
+        assert_eq!(tok.decode_all(&ids), "aé");
+    }
+
+    #[test]
+    fn rejects_truncated_unsupported_and_incomplete_models() {
+        let data = model(MODEL_BPE, 0..256);
+        assert!(parse_sentencepiece(&data[..data.len() - 1]).is_err());
+        assert!(parse_sentencepiece(&data[..data.len() / 2]).is_err());
+        // Unigram models are not supported
+        assert!(parse_sentencepiece(&model(1, 0..256)).is_err());
+        assert!(parse_sentencepiece(&model(MODEL_BPE, 0..255)).is_err());
+        // Without any byte pieces there is simply no byte fallback
+        let tok = parse_sentencepiece(&model(MODEL_BPE, 0..0)).unwrap();
+        assert!(tok.byte_tokens.is_empty());
+    }
+}
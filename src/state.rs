@@ -1,11 +1,15 @@
 //! Runtime state buffers for Llama inference.
 
+use crate::cache::CachePolicy;
 use crate::config::LlamaConfig;
+use crate::error::Result;
+use crate::ops::rope_table;
 
 /// Runtime buffers for inference, aligned with forward pass states.
 #[derive(Debug, Clone)]
 pub struct LlamaState {
-    /// Current hidden state (hidden_states)
+    /// Current hidden state (hidden_states); after `forward` returns, the
+    /// final-normed last hidden state of the token just processed
     pub x: Vec<f32>,
     /// Buffer for attention output before projection
     pub xb: Vec<f32>,
@@ -25,20 +29,44 @@ pub struct LlamaState {
     pub att: Vec<Vec<f32>>,
     /// Output logits
     pub logits: Vec<f32>,
-    /// Key cache [n_layers][seq_len * kv_dim]
+    /// Key cache [n_layers][seq_len * kv_dim]; keys are stored unrotated
+    /// when the cache policy evicts
     pub key_cache: Vec<Vec<f32>>,
     /// Value cache [n_layers][seq_len * kv_dim]
     pub value_cache: Vec<Vec<f32>>,
+    /// Eviction policy for the KV cache
+    pub cache_policy: CachePolicy,
+    /// Number of occupied KV cache slots
+    pub cache_len: usize,
+    /// Attention mass received by each cache slot, summed over heads and
+    /// layers; only tracked when the cache policy evicts
+    pub att_mass: Vec<f32>,
+    /// Under [`CachePolicy::Streaming`], the window slot (counted from the
+    /// first slot after the sinks) holding the oldest token
+    pub ring_start: usize,
+    /// RoPE `(cos, sin)` per cache slot [seq_len][head_size / 2], used to
+    /// rotate unrotated keys by their current slot; empty without eviction
+    pub rope: Vec<(f32, f32)>,
 }
 
 impl LlamaState {
     /// Allocate inference buffers based on config.
     pub fn new(config: &LlamaConfig) -> Self {
+        Self::alloc(config, CachePolicy::Full)
+    }
+
+    /// Allocate inference buffers with a KV cache sized for `policy`.
+    pub fn with_cache_policy(config: &LlamaConfig, policy: CachePolicy) -> Result<Self> {
+        policy.validate(config.seq_len as usize)?;
+        Ok(Self::alloc(config, policy))
+    }
+
+    fn alloc(config: &LlamaConfig, cache_policy: CachePolicy) -> Self {
         let dim = config.dim as usize;
         let hdim = config.hidden_dim as usize;
         let n_heads = config.n_heads as usize;
         let n_layers = config.n_layers as usize;
-        let seq_len = config.seq_len as usize;
+        let seq_len = cache_policy.capacity(config.seq_len as usize);
         let kv_dim = config.kv_dim();
         let vocab_size = config.vocab_size as usize;
 
@@ -63,6 +91,145 @@ pub fn new(config: &LlamaConfig) -> Self {
             logits: vec![0.0; vocab_size],
             key_cache,
             value_cache,
+            cache_policy,
+            cache_len: 0,
+            att_mass: vec![0.0; seq_len],
+            ring_start: 0,
+            rope: if cache_policy.evicts() {
+                rope_table(seq_len, config.head_size())
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    /// Returns the KV cache capacity in positions.
+    #[inline]
+    pub fn cache_capacity(&self) -> usize {
+        self.att[0].len()
+    }
+
+    /// Reserve the cache slot for the token at `pos`, evicting if the cache is full.
+    ///
+    /// With [`CachePolicy::Full`] the slot is `pos` itself; eviction policies
+    /// append after the occupied slots instead. Once full, the streaming
+    /// window is a ring buffer whose oldest slot is overwritten in place,
+    /// while heavy-hitter eviction compacts the cache.
+    pub fn next_slot(&mut self, pos: i32, config: &LlamaConfig) -> usize {
+        let full = self.cache_len == self.cache_capacity();
+        let slot = match self.cache_policy {
+            CachePolicy::Full => pos as usize,
+            CachePolicy::Streaming { n_sink, window } if full => {
+                let slot = n_sink + self.ring_start;
+                self.ring_start = (self.ring_start + 1) % window;
+                slot
+            }
+            CachePolicy::HeavyHitter { .. } if full => {
+                if let Some(victim) = self.cache_policy.victim(&self.att_mass[..self.cache_len]) {
+                    self.evict(victim, config);
+                }
+                self.cache_len
+            }
+            _ => self.cache_len,
+        };
+        self.cache_len = match self.cache_policy {
+            CachePolicy::Full => slot + 1,
+            _ => self.cache_len.max(slot + 1),
+        };
+        self.att_mass[slot] = 0.0;
+        slot
+    }
+
+    /// Returns the RoPE position of the token cached in `slot`, which is its
+    /// index in cache order: sinks first, then the window from oldest to newest.
+    #[inline]
+    pub fn position(&self, slot: usize) -> usize {
+        match self.cache_policy {
+            CachePolicy::Streaming { n_sink, window } if slot >= n_sink => {
+                n_sink + (slot - n_sink + window - self.ring_start) % window
+            }
+            _ => slot,
+        }
+    }
+
+    /// Remove `slot` from every layer's KV cache, shifting later entries down.
+    ///
+    /// Keys are cached unrotated under eviction and rotated by their position
+    /// in attention, so shifted keys take their new RoPE positions without
+    /// being rewritten.
+    pub fn evict(&mut self, slot: usize, config: &LlamaConfig) {
+        let kv_dim = config.kv_dim();
+        let len = self.cache_len;
+
+        for (keys, values) in self.key_cache.iter_mut().zip(self.value_cache.iter_mut()) {
+            keys.copy_within((slot + 1) * kv_dim..len * kv_dim, slot * kv_dim);
+            values.copy_within((slot + 1) * kv_dim..len * kv_dim, slot * kv_dim);
+        }
+        self.att_mass.copy_within(slot + 1..len, slot);
+        self.cache_len = len - 1;
+    }
+
+    /// Overwrite this state's KV cache with the occupied part of `src`.
+    ///
+    /// Used to branch decoding paths without reallocating: only the first
+    /// `cache_len` rows of each layer are copied. Both states must share a config.
+    pub fn copy_cache_from(&mut self, src: &LlamaState) {
+        let n = src.cache_len * (src.key_cache[0].len() / src.cache_capacity());
+        for (dst, src) in self.key_cache.iter_mut().zip(&src.key_cache) {
+            dst[..n].copy_from_slice(&src[..n]);
+        }
+        for (dst, src) in self.value_cache.iter_mut().zip(&src.value_cache) {
+            dst[..n].copy_from_slice(&src[..n]);
+        }
+        self.att_mass[..src.cache_len].copy_from_slice(&src.att_mass[..src.cache_len]);
+        self.cache_policy = src.cache_policy;
+        self.cache_len = src.cache_len;
+        self.ring_start = src.ring_start;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heavy_hitter_evicts_least_attended_slot_and_shifts_mass() {
+        let config = LlamaConfig {
+            dim: 8,
+            hidden_dim: 16,
+            n_layers: 2,
+            n_heads: 2,
+            n_kv_heads: 1,
+            vocab_size: 8,
+            seq_len: 8,
+        };
+        let kv_dim = config.kv_dim();
+        let policy = CachePolicy::HeavyHitter {
+            budget: 4,
+            n_recent: 1,
+        };
+        let mut state = LlamaState::with_cache_policy(&config, policy).unwrap();
+        // Tag each token's cache rows with its index
+        let add = |state: &mut LlamaState, token: usize, mass: f32| {
+            let slot = state.next_slot(token as i32, &config);
+            for cache in state.key_cache.iter_mut().chain(&mut state.value_cache) {
+                cache[slot * kv_dim..(slot + 1) * kv_dim].fill(token as f32);
+            }
+            state.att_mass[slot] = mass;
+            slot
+        };
+        for (token, mass) in [5.0, 1.0, 3.0, 0.5].into_iter().enumerate() {
+            assert_eq!(add(&mut state, token, mass), token);
+        }
+
+        // Token 3 has the least mass but is recent; token 1 is dropped
+        assert_eq!(add(&mut state, 4, 0.0), 3);
+        assert_eq!(state.cache_len, 4);
+        assert_eq!(state.att_mass, [5.0, 3.0, 0.5, 0.0]);
+        for cache in state.key_cache.iter().chain(&state.value_cache) {
+            let tags: Vec<f32> = cache.chunks(kv_dim).map(|row| row[0]).collect();
+            assert_eq!(tags[..4], [0.0, 2.0, 3.0, 4.0]);
         }
+        assert_eq!(state.position(3), 3);
     }
 }
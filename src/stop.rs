@@ -0,0 +1,131 @@
+//! Stop strings matched on detokenized output.
+
+/// Detects stop strings in streamed output, holding back text that may be
+/// the start of a match until it is resolved.
+///
+/// Matching works on bytes, so stop strings are found even when they span
+/// several tokens or split a UTF-8 character across byte-fallback tokens.
+#[derive(Debug, Clone, Default)]
+pub struct StopMatcher {
+    pub stops: Vec<Vec<u8>>,
+    held: Vec<u8>,
+}
+
+impl StopMatcher {
+    pub fn new<S: AsRef<str>>(stops: &[S]) -> Self {
+        StopMatcher {
+            stops: stops
+                .iter()
+                .map(|s| s.as_ref().as_bytes().to_vec())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            held: Vec::new(),
+        }
+    }
+
+    /// Feed the next piece of output. Bytes that can no longer be part of a
+    /// stop string are appended to `out`. Returns `true` once a stop string
+    /// matched; the match and anything after it are dropped.
+    pub fn push(&mut self, bytes: &[u8], out: &mut Vec<u8>) -> bool {
+        self.held.extend_from_slice(bytes);
+
+        // Earliest match wins
+        let found = self
+            .stops
+            .iter()
+            .filter_map(|stop| find(&self.held, stop))
+            .min();
+        if let Some(start) = found {
+            out.extend_from_slice(&self.held[..start]);
+            self.held.clear();
+            return true;
+        }
+
+        // Keep the longest suffix that is a prefix of some stop string
+        let keep = (1..self.held.len().min(self.max_len()) + 1)
+            .rev()
+            .find(|&k| {
+                let tail = &self.held[self.held.len() - k..];
+                self.stops.iter().any(|stop| stop.starts_with(tail))
+            })
+            .unwrap_or(0);
+        let emit = self.held.len() - keep;
+        out.extend(self.held.drain(..emit));
+        false
+    }
+
+    /// Release any held-back bytes, e.g. when generation ends for another reason.
+    pub fn flush(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.held);
+    }
+
+    fn max_len(&self) -> usize {
+        self.stops.iter().map(Vec::len).max().unwrap_or(0)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `pieces` one at a time, returning the emitted text and whether a stop matched.
+    fn run(stops: &[&str], pieces: &[&[u8]]) -> (Vec<u8>, bool) {
+        let mut matcher = StopMatcher::new(stops);
+        let mut out = Vec::new();
+        for piece in pieces {
+            if matcher.push(piece, &mut out) {
+                return (out, true);
+            }
+        }
+        matcher.flush(&mut out);
+        (out, false)
+    }
+
+    #[test]
+    fn stops_across_pieces() {
+        assert_eq!(
+            run(&["\nUser:"], &[b"Hi", b" there\n", b"Us", b"er", b": more"]),
+            (b"Hi there".to_vec(), true)
+        );
+        assert_eq!(
+            run(&["end", "stop"], &[b"a st", b"op and end"]),
+            (b"a ".to_vec(), true)
+        );
+    }
+
+    #[test]
+    fn holds_back_only_possible_prefixes() {
+        let mut matcher = StopMatcher::new(&["abc"]);
+        let mut out = Vec::new();
+        assert!(!matcher.push(b"xab", &mut out));
+        assert_eq!(out, b"x");
+        assert!(!matcher.push(b"d", &mut out));
+        assert_eq!(out, b"xabd");
+    }
+
+    #[test]
+    fn flushes_partial_match_at_end() {
+        assert_eq!(
+            run(&["###"], &[b"text #", b"#"]),
+            (b"text ##".to_vec(), false)
+        );
+    }
+
+    #[test]
+    fn matches_utf8_split_across_byte_tokens() {
+        let [b0, b1] = "é".as_bytes() else { panic!() };
+        assert_eq!(
+            run(&["é!"], &[b"caf", &[*b0], &[*b1], b"!"]),
+            (b"caf".to_vec(), true)
+        );
+    }
+
+    #[test]
+    fn empty_stop_strings_are_ignored() {
+        assert_eq!(run(&[""], &[b"abc"]), (b"abc".to_vec(), false));
+    }
+}
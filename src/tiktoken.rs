@@ -0,0 +1,404 @@
+//! Byte-level BPE as used by Llama 3, loaded from a tiktoken rank file.
+//!
+//! Text is split into chunks by the Llama 3 pre-tokenizer pattern, and each
+//! chunk is merged from single bytes, always joining the adjacent pair whose
+//! concatenation has the lowest rank.
+
+use crate::error::{LlamaError, Result};
+use crate::tokenizer::{Tokenize, merge_pairs_with_starts};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Special tokens that follow the ranked tokens in Llama 3; the remaining
+/// slots hold `<|reserved_special_token_N|>`.
+const LLAMA3_SPECIAL_TOKENS: [&str; 10] = [
+    "<|begin_of_text|>",
+    "<|end_of_text|>",
+    "<|reserved_special_token_0|>",
+    "<|reserved_special_token_1|>",
+    "<|reserved_special_token_2|>",
+    "<|reserved_special_token_3|>",
+    "<|start_header_id|>",
+    "<|end_header_id|>",
+    "<|reserved_special_token_4|>",
+    "<|eot_id|>",
+];
+
+/// Byte-level BPE tokenizer with rank-ordered merges.
+#[derive(Debug, Clone)]
+pub struct TiktokenTokenizer {
+    /// Bytes of each ranked token; special tokens hold their name
+    pub pieces: Vec<Vec<u8>>,
+    /// Token id of each ranked byte sequence; the id is the merge rank
+    pub ranks: HashMap<Vec<u8>, i32>,
+    /// Special tokens by name, e.g. `<|eot_id|>`; they stand for no text
+    pub special_tokens: HashMap<String, i32>,
+    pub bos_id: i32,
+    pub eos_id: i32,
+    /// Token for each single byte value
+    byte_tokens: Vec<i32>,
+}
+
+impl TiktokenTokenizer {
+    /// Encode text using byte-level BPE, with optional BOS/EOS tokens.
+    pub fn encode(&self, text: &str, bos: bool, eos: bool) -> Result<Vec<i32>> {
+        let tokens = self.encode_with_offsets(text, bos, eos)?;
+        Ok(tokens.into_iter().map(|(id, _, _)| id).collect())
+    }
+
+    /// Encode text, pairing each token with the byte range of `text` it covers.
+    pub fn encode_with_offsets(
+        &self,
+        text: &str,
+        bos: bool,
+        eos: bool,
+    ) -> Result<Vec<(i32, usize, usize)>> {
+        let mut tokens = Vec::with_capacity(text.len() / 3 + 2);
+        if bos {
+            tokens.push((self.bos_id, 0, 0));
+        }
+        let mut buf = Vec::new();
+        for (offset, chunk) in split_chunks(text) {
+            if let Some(&id) = self.ranks.get(chunk.as_bytes()) {
+                tokens.push((id, offset, offset + chunk.len()));
+                continue;
+            }
+            let mut symbols: Vec<i32> = chunk
+                .bytes()
+                .map(|b| self.byte_tokens[b as usize])
+                .collect();
+            let mut starts: Vec<usize> = (0..chunk.len()).collect();
+            merge_pairs_with_starts(&mut symbols, &mut starts, |a, b| {
+                buf.clear();
+                buf.extend_from_slice(&self.pieces[a as usize]);
+                buf.extend_from_slice(&self.pieces[b as usize]);
+                let &id = self.ranks.get(&buf)?;
+                Some((-(id as f32), id))
+            });
+            for (k, (&id, &start)) in symbols.iter().zip(&starts).enumerate() {
+                let end = starts.get(k + 1).copied().unwrap_or(chunk.len());
+                tokens.push((id, offset + start, offset + end));
+            }
+        }
+        if eos {
+            tokens.push((self.eos_id, text.len(), text.len()));
+        }
+        Ok(tokens)
+    }
+
+    /// Returns true for special tokens, which stand for no text.
+    pub fn is_control(&self, token: i32) -> bool {
+        self.pieces
+            .get(token as usize)
+            .and_then(|p| std::str::from_utf8(p).ok())
+            .is_some_and(|name| self.special_tokens.get(name) == Some(&token))
+    }
+
+    /// Returns the raw bytes a token stands for. Special tokens yield an empty vector.
+    pub fn piece_bytes(&self, token: i32) -> Option<Vec<u8>> {
+        if self.is_control(token) {
+            return Some(Vec::new());
+        }
+        self.pieces.get(token as usize).cloned()
+    }
+}
+
+impl Tokenize for TiktokenTokenizer {
+    fn encode(&self, text: &str, bos: bool, eos: bool) -> Result<Vec<i32>> {
+        TiktokenTokenizer::encode(self, text, bos, eos)
+    }
+
+    fn encode_with_offsets(
+        &self,
+        text: &str,
+        bos: bool,
+        eos: bool,
+    ) -> Result<Vec<(i32, usize, usize)>> {
+        TiktokenTokenizer::encode_with_offsets(self, text, bos, eos)
+    }
+
+    fn piece_bytes(&self, token: i32) -> Option<Vec<u8>> {
+        TiktokenTokenizer::piece_bytes(self, token)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.pieces.len()
+    }
+
+    fn token_id(&self, piece: &str) -> Option<i32> {
+        self.special_tokens
+            .get(piece)
+            .or_else(|| self.ranks.get(piece.as_bytes()))
+            .copied()
+    }
+
+    fn special_tokens(&self) -> &HashMap<String, i32> {
+        &self.special_tokens
+    }
+
+    fn bos_id(&self) -> i32 {
+        self.bos_id
+    }
+
+    fn eos_id(&self) -> i32 {
+        self.eos_id
+    }
+
+    fn add_dummy_prefix(&self) -> bool {
+        false
+    }
+}
+
+/// Load a tiktoken rank file (`<base64 token> <rank>` per line), as shipped
+/// with Llama 3 as `tokenizer.model`.
+///
+/// Ids from the number of ranked tokens up to `vocab_size` are the Llama 3
+/// special tokens, so `vocab_size` must leave room for at least BOS and EOS.
+pub fn load_tiktoken<P: AsRef<Path>>(path: P, vocab_size: usize) -> Result<TiktokenTokenizer> {
+    parse_tiktoken(&std::fs::read_to_string(path)?, vocab_size)
+}
+
+/// Build a tokenizer from the contents of a tiktoken rank file.
+pub fn parse_tiktoken(src: &str, vocab_size: usize) -> Result<TiktokenTokenizer> {
+    let mut ranked: Vec<(i32, Vec<u8>)> = Vec::new();
+    for (n, line) in src.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (token, rank) = line
+            .split_once(' ')
+            .ok_or_else(|| tik_err(n, "expected '<base64> <rank>'"))?;
+        let bytes = decode_base64(token).ok_or_else(|| tik_err(n, "invalid base64"))?;
+        let rank = rank
+            .trim()
+            .parse()
+            .map_err(|_| tik_err(n, "invalid rank"))?;
+        ranked.push((rank, bytes));
+    }
+    ranked.sort_unstable_by_key(|&(rank, _)| rank);
+
+    let n_ranks = ranked.len();
+    if vocab_size < n_ranks + 2 {
+        return Err(LlamaError::Tokenizer(format!(
+            "tiktoken: vocab size {vocab_size} leaves no room for special tokens after {n_ranks} ranks"
+        )));
+    }
+    let mut pieces = Vec::with_capacity(vocab_size);
+    let mut ranks = HashMap::with_capacity(n_ranks);
+    for (id, (rank, bytes)) in ranked.into_iter().enumerate() {
+        if rank != id as i32 {
+            return Err(LlamaError::Tokenizer(format!(
+                "tiktoken: ranks must run from 0 without gaps, found {rank} at {id}"
+            )));
+        }
+        ranks.insert(bytes.clone(), rank);
+        pieces.push(bytes);
+    }
+    let byte_tokens = (0..=255u8)
+        .map(|b| ranks.get(&[b][..]).copied())
+        .collect::<Option<Vec<i32>>>()
+        .ok_or_else(|| LlamaError::Tokenizer("tiktoken: missing single-byte tokens".into()))?;
+
+    let mut special_tokens = HashMap::new();
+    for i in 0..vocab_size - n_ranks {
+        let name = match LLAMA3_SPECIAL_TOKENS.get(i) {
+            Some(name) => name.to_string(),
+            None => format!("<|reserved_special_token_{}|>", i - 5),
+        };
+        let id = (n_ranks + i) as i32;
+        pieces.push(name.clone().into_bytes());
+        special_tokens.insert(name, id);
+    }
+
+    Ok(TiktokenTokenizer {
+        pieces,
+        ranks,
+        special_tokens,
+        bos_id: n_ranks as i32,
+        eos_id: n_ranks as i32 + 1,
+        byte_tokens,
+    })
+}
+
+fn tik_err(line: usize, msg: &str) -> LlamaError {
+    LlamaError::Tokenizer(format!("tiktoken: line {}: {msg}", line + 1))
+}
+
+/// Decode standard base64 with `=` padding.
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Split text as the Llama 3 pre-tokenizer pattern does, returning each
+/// chunk with its byte offset:
+///
+/// ```text
+/// (?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+
+/// ```
+///
+/// `\p{L}` is approximated by alphabetic chars that are not numeric, which
+/// differs only for combining marks in some scripts.
+fn split_chunks(text: &str) -> Vec<(usize, &str)> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let cs: Vec<char> = chars.iter().map(|&(_, c)| c).collect();
+    let mut chunks = Vec::new();
+    let mut i = 0;
+    while i < cs.len() {
+        let end = i + chunk_len(&cs[i..]);
+        let stop = chars.get(end).map_or(text.len(), |&(offset, _)| offset);
+        chunks.push((chars[i].0, &text[chars[i].0..stop]));
+        i = end;
+    }
+    chunks
+}
+
+/// Length in chars of the chunk at the start of `cs`, trying each
+/// alternative of the pattern in order.
+fn chunk_len(cs: &[char]) -> usize {
+    let count = |from: usize, pred: fn(char) -> bool| {
+        cs.get(from..)
+            .map_or(0, |rest| rest.iter().take_while(|&&c| pred(c)).count())
+    };
+    let c = cs[0];
+
+    // (?i:'s|'t|'re|'ve|'m|'ll|'d)
+    if c == '\'' {
+        for suffix in ["s", "t", "re", "ve", "m", "ll", "d"] {
+            let n = suffix.len();
+            if cs.len() > n
+                && cs[1..=n]
+                    .iter()
+                    .zip(suffix.chars())
+                    .all(|(a, b)| a.to_ascii_lowercase() == b)
+            {
+                return n + 1;
+            }
+        }
+    }
+    // [^\r\n\p{L}\p{N}]?\p{L}+
+    let lead = usize::from(!is_newline(c) && !is_letter(c) && !is_number(c));
+    let letters = count(lead, is_letter);
+    if letters > 0 {
+        return lead + letters;
+    }
+    // \p{N}{1,3}
+    let digits = count(0, is_number).min(3);
+    if digits > 0 {
+        return digits;
+    }
+    // ` ?[^\s\p{L}\p{N}]+[\r\n]*`
+    let lead = usize::from(c == ' ');
+    let symbols = count(lead, is_symbol);
+    if symbols > 0 {
+        return lead + symbols + count(lead + symbols, is_newline);
+    }
+    // \s*[\r\n]+
+    let spaces = count(0, char::is_whitespace);
+    if let Some(last) = cs[..spaces].iter().rposition(|&c| is_newline(c)) {
+        return last + 1;
+    }
+    // \s+(?!\S), leaving the last space to the following word
+    if spaces > 1 && spaces < cs.len() {
+        return spaces - 1;
+    }
+    // \s+
+    spaces.max(1)
+}
+
+fn is_letter(c: char) -> bool {
+    c.is_alphabetic() && !c.is_numeric()
+}
+
+fn is_number(c: char) -> bool {
+    c.is_numeric()
+}
+
+fn is_newline(c: char) -> bool {
+    c == '\r' || c == '\n'
+}
+
+fn is_symbol(c: char) -> bool {
+    !c.is_whitespace() && !is_letter(c) && !is_number(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::assert_spans_tile;
+
+    fn base64(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = String::new();
+        for chunk in bytes.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+            for i in 0..=chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            }
+        }
+        while !out.len().is_multiple_of(4) {
+            out.push('=');
+        }
+        out
+    }
+
+    /// Single bytes ranked by value, then a few merges.
+    fn tokenizer() -> TiktokenTokenizer {
+        let mut pieces: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+        for merged in ["he", "ll", "llo", " w", "or", " wor", "日本"] {
+            pieces.push(merged.as_bytes().to_vec());
+        }
+        let src: String = pieces
+            .iter()
+            .enumerate()
+            .map(|(rank, bytes)| format!("{} {rank}\n", base64(bytes)))
+            .collect();
+        parse_tiktoken(&src, pieces.len() + 2).unwrap()
+    }
+
+    #[test]
+    fn merges_by_rank() {
+        let tok = tokenizer();
+        assert_eq!(decode_base64(&base64(b"hello")).unwrap(), b"hello");
+        assert_eq!(
+            tok.encode_with_offsets("hello world", true, false).unwrap(),
+            [
+                (tok.bos_id, 0, 0),
+                (256, 0, 2),
+                (258, 2, 5),
+                (261, 5, 9),
+                (b'l' as i32, 9, 10),
+                (b'd' as i32, 10, 11)
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_tile_the_text() {
+        let tok = tokenizer();
+        for text in ["", "hello world", "it's 1234 日本語!!\n\n  x", "  \tend "] {
+            assert_spans_tile(&tok, text, false);
+        }
+    }
+}
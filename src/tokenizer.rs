@@ -1,33 +1,465 @@
 //! Tokenizer loading and BPE encoding.
 
 use crate::error::{LlamaError, Result};
-use byteorder::{LittleEndian, ReadBytesExt};
-use std::collections::HashMap;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{BinaryHeap, HashMap};
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::Path;
 
+/// Operations shared by the tokenizer variants, so that sampling, constraints
+/// and the CLI work with any of them.
+pub trait Tokenize {
+    /// Encode text, with optional BOS/EOS tokens.
+    ///
+    /// Special token names in `text` are encoded as plain text and never as
+    /// their ids, so this is the mode for untrusted input.
+    fn encode(&self, text: &str, bos: bool, eos: bool) -> Result<Vec<i32>>;
+
+    /// Encode text, pairing each token with the byte range `start..end` of
+    /// `text` it stands for. Concatenated in order, the ranges cover the text,
+    /// apart from leading and trailing whitespace that normalization drops.
+    fn encode_with_offsets(
+        &self,
+        text: &str,
+        bos: bool,
+        eos: bool,
+    ) -> Result<Vec<(i32, usize, usize)>>;
+
+    /// Encode text that continues earlier text, such as a single word of a
+    /// CLI token spec: no BOS or EOS, no dummy prefix, and no trimming of
+    /// leading or trailing whitespace.
+    fn encode_continuation(&self, text: &str) -> Result<Vec<i32>> {
+        self.encode(text, false, false)
+    }
+
+    /// Encode text in which special token names, e.g. `<|eot_id|>` or `</s>`,
+    /// stand for their single ids. The text between them is encoded with
+    /// [`encode`](Tokenize::encode).
+    fn encode_with_special(&self, text: &str, bos: bool, eos: bool) -> Result<Vec<i32>> {
+        let mut tokens = Vec::with_capacity(text.len() + 2);
+        if bos {
+            tokens.push(self.bos_id());
+        }
+        for (part, special) in split_special(text, self.special_tokens()) {
+            match special {
+                Some(id) => tokens.push(id),
+                None => tokens.extend(self.encode(part, false, false)?),
+            }
+        }
+        if eos {
+            tokens.push(self.eos_id());
+        }
+        Ok(tokens)
+    }
+
+    /// Special tokens by name; they stand for no text.
+    fn special_tokens(&self) -> &HashMap<String, i32>;
+
+    /// Returns the raw bytes a token stands for; empty for special tokens.
+    fn piece_bytes(&self, token: i32) -> Option<Vec<u8>>;
+
+    /// Number of token ids, including special tokens.
+    fn vocab_size(&self) -> usize;
+
+    /// Look up the id of an exact vocabulary entry or special token, e.g. `<|eot_id|>`.
+    fn token_id(&self, piece: &str) -> Option<i32>;
+
+    fn bos_id(&self) -> i32;
+
+    fn eos_id(&self) -> i32;
+
+    /// Returns true if encoding prepends a space that decoding should drop after BOS.
+    fn add_dummy_prefix(&self) -> bool;
+}
+
+impl dyn Tokenize + '_ {
+    /// Decode a token sequence into text.
+    pub fn decode_all(&self, tokens: &[i32]) -> String {
+        Detokenizer::new(self).decode_all(tokens)
+    }
+}
+
 /// Tokenizer holding vocabulary and scores.
+///
+/// Pieces spell the SentencePiece word boundary `▁` as a plain space.
 #[derive(Debug, Clone)]
 pub struct Tokenizer {
     pub vocab: Vec<String>,
     pub scores: Vec<f32>,
     pub vocab_map: HashMap<String, i32>,
     pub max_token_len: u32,
+    /// Rank and result of each mergeable token pair, for merge-list BPE as in
+    /// Hugging Face `tokenizer.json`. When empty, adjacent pieces merge by the
+    /// score of their concatenation, as in SentencePiece.
+    pub merges: HashMap<(i32, i32), (u32, i32)>,
+    /// Special tokens by name, e.g. `<s>`; they stand for no text
+    pub special_tokens: HashMap<String, i32>,
+    pub bos_id: i32,
+    pub eos_id: i32,
+    /// Token for characters missing from the vocabulary without byte fallback
+    pub unk_id: Option<i32>,
+    /// Byte-fallback token for each byte value; empty if there is no byte fallback
+    pub byte_tokens: Vec<i32>,
+    /// Prepend a space to non-empty input (SentencePiece's dummy prefix)
+    pub add_dummy_prefix: bool,
+    /// Split the input before every space and merge each part separately
+    pub split_on_space: bool,
+    /// Drop leading and trailing spaces and collapse runs of spaces, as
+    /// SentencePiece's normalizer does
+    pub remove_extra_whitespaces: bool,
 }
 
 impl Tokenizer {
     /// Encode text using BPE, with optional BOS/EOS tokens.
     pub fn encode(&self, text: &str, bos: bool, eos: bool) -> Result<Vec<i32>> {
-        bpe_encode(text, &self.vocab, &self.scores, &self.vocab_map, bos, eos)
+        let tokens = self.encode_with_offsets(text, bos, eos)?;
+        Ok(tokens.into_iter().map(|(id, _, _)| id).collect())
+    }
+
+    /// Encode text like [`encode`](Tokenizer::encode), pairing each token
+    /// with the byte range `start..end` of `text` that it covers.
+    ///
+    /// A token starting with the dummy prefix spans only the text after it,
+    /// so the prefix alone, like BOS and EOS, has an empty span. Each byte
+    /// fallback token covers its single byte. With `remove_extra_whitespaces`,
+    /// a collapsed run of spaces belongs to the token of the space kept for
+    /// it, and dropped leading and trailing spaces belong to no token.
+    pub fn encode_with_offsets(
+        &self,
+        text: &str,
+        bos: bool,
+        eos: bool,
+    ) -> Result<Vec<(i32, usize, usize)>> {
+        self.encode_spans(text, bos, eos, false)
+    }
+
+    /// Encode with offsets; a `continuation` skips the dummy prefix and the
+    /// trimming of surrounding whitespace.
+    fn encode_spans(
+        &self,
+        text: &str,
+        bos: bool,
+        eos: bool,
+        continuation: bool,
+    ) -> Result<Vec<(i32, usize, usize)>> {
+        let mut tokens = Vec::with_capacity(text.len() + 3);
+        if bos {
+            tokens.push((self.bos_id, 0, 0));
+        }
+
+        let (normalized, spans) = self.normalize(text, continuation)?;
+        let parts = if self.split_on_space {
+            split_before_spaces(&normalized)
+        } else {
+            vec![(0, normalized.as_str())]
+        };
+        let mut buf = String::new();
+        for (offset, part) in parts {
+            let (mut symbols, mut starts) = self.symbols(part)?;
+            if self.merges.is_empty() {
+                merge_pairs_with_starts(&mut symbols, &mut starts, |a, b| {
+                    buf.clear();
+                    buf.push_str(&self.vocab[a as usize]);
+                    buf.push_str(&self.vocab[b as usize]);
+                    let &id = self.vocab_map.get(buf.as_str())?;
+                    if self.is_control(id) {
+                        return None;
+                    }
+                    Some((self.scores[id as usize], id))
+                });
+            } else {
+                merge_pairs_with_starts(&mut symbols, &mut starts, |a, b| {
+                    let &(rank, id) = self.merges.get(&(a, b))?;
+                    Some((-(rank as f32), id))
+                });
+            }
+            for (k, (&id, &start)) in symbols.iter().zip(&starts).enumerate() {
+                let end = starts.get(k + 1).copied().unwrap_or(part.len());
+                let (first, last) = (spans[offset + start], spans[offset + end - 1]);
+                tokens.push((id, first.0, last.1));
+            }
+        }
+
+        if eos {
+            tokens.push((self.eos_id, text.len(), text.len()));
+        }
+        Ok(tokens)
+    }
+
+    /// Apply whitespace removal and the dummy prefix, returning the normalized
+    /// text along with the source byte range of each of its bytes.
+    fn normalize(&self, text: &str, continuation: bool) -> Result<(String, Vec<(usize, usize)>)> {
+        let mut chars: Vec<(usize, char)> = text.char_indices().collect();
+        if self.remove_extra_whitespaces {
+            let mut prev_space = !continuation;
+            chars.retain(|&(_, c)| {
+                let keep = c != ' ' || !prev_space;
+                prev_space = c == ' ';
+                keep
+            });
+            if !continuation && chars.last().is_some_and(|&(_, c)| c == ' ') {
+                chars.pop();
+            }
+        }
+
+        let mut normalized = String::with_capacity(text.len() + 1);
+        let mut spans = Vec::with_capacity(text.len() + 1);
+        if self.add_dummy_prefix && !continuation && !chars.is_empty() {
+            if !self.vocab_map.contains_key(" ") {
+                return Err(LlamaError::Tokenizer(
+                    "dummy prefix ' ' not found in vocabulary".into(),
+                ));
+            }
+            normalized.push(' ');
+            spans.push((chars[0].0, chars[0].0));
+        }
+        for (offset, c) in chars {
+            normalized.push(c);
+            if c == ' ' && self.remove_extra_whitespaces {
+                // The kept space stands for the whole run it collapsed
+                let run = &text[offset..];
+                spans.push((
+                    offset,
+                    offset + run.len() - run.trim_start_matches(' ').len(),
+                ));
+            } else {
+                spans.extend((offset..offset + c.len_utf8()).map(|i| (i, i + 1)));
+            }
+        }
+        Ok((normalized, spans))
+    }
+
+    /// Register a vocabulary entry as a special token, e.g. a chat marker such
+    /// as `<|im_start|>` that `tokenizer.bin` stores as an ordinary piece.
+    pub fn add_special_token(&mut self, name: &str) -> Result<i32> {
+        let &id = self
+            .vocab_map
+            .get(name)
+            .ok_or_else(|| LlamaError::Tokenizer(format!("{name:?} not in vocabulary")))?;
+        self.special_tokens.insert(name.to_string(), id);
+        Ok(id)
+    }
+
+    /// Initial symbols for BPE: one token per character, with byte fallback
+    /// or the unknown token for characters missing from the vocabulary.
+    /// Also returns the byte offset in `text` where each symbol starts.
+    fn symbols(&self, text: &str) -> Result<(Vec<i32>, Vec<usize>)> {
+        let mut symbols = Vec::with_capacity(text.len());
+        let mut starts = Vec::with_capacity(text.len());
+        let mut buf = [0u8; 4];
+        for (offset, c) in text.char_indices() {
+            let s = c.encode_utf8(&mut buf);
+            if let Some(&id) = self.vocab_map.get(&*s) {
+                symbols.push(id);
+                starts.push(offset);
+            } else if !self.byte_tokens.is_empty() {
+                symbols.extend(s.bytes().map(|b| self.byte_tokens[b as usize]));
+                starts.extend(offset..offset + s.len());
+            } else if let Some(unk) = self.unk_id {
+                symbols.push(unk);
+                starts.push(offset);
+            } else {
+                return Err(LlamaError::Tokenizer(format!(
+                    "character {c:?} not in vocabulary"
+                )));
+            }
+        }
+        Ok((symbols, starts))
     }
 
     /// Decode a token ID to its string representation.
     pub fn decode(&self, token: i32) -> Option<&str> {
         self.vocab.get(token as usize).map(|s| s.as_str())
     }
+
+    /// Returns true for special tokens such as `<unk>`, `<s>` and `</s>`, which stand for no text.
+    pub fn is_control(&self, token: i32) -> bool {
+        self.decode(token)
+            .is_some_and(|piece| self.special_tokens.get(piece.trim()) == Some(&token))
+    }
+
+    /// Returns the raw bytes a token stands for, resolving byte-fallback tokens.
+    ///
+    /// Byte tokens are either spelled `<0xNN>` or, as in llama2.c exports,
+    /// stored as the raw byte at id `byte + 3`. Control tokens yield an empty vector.
+    pub fn piece_bytes(&self, token: i32) -> Option<Vec<u8>> {
+        if self.is_control(token) {
+            return Some(Vec::new());
+        }
+        let piece = self.decode(token)?;
+        if let Some(byte) = byte_token(piece) {
+            return Some(vec![byte]);
+        }
+        if let Some(byte) = self.byte_value(token) {
+            return Some(vec![byte]);
+        }
+        Some(piece.as_bytes().to_vec())
+    }
+
+    /// Returns the byte a byte-fallback token stands for.
+    fn byte_value(&self, token: i32) -> Option<u8> {
+        let (&first, &last) = (self.byte_tokens.first()?, self.byte_tokens.last()?);
+        if last - first == 255 {
+            // 256 ascending ids spanning 256 values are contiguous, as in the
+            // llama2.c layout
+            let b = token.checked_sub(first).filter(|b| (0..256).contains(b))?;
+            return (self.byte_tokens[b as usize] == token).then_some(b as u8);
+        }
+        self.byte_tokens
+            .iter()
+            .position(|&t| t == token)
+            .map(|b| b as u8)
+    }
+
+    /// Decode a token sequence into text, stripping the space after BOS.
+    pub fn decode_all(&self, tokens: &[i32]) -> String {
+        Detokenizer::new(self).decode_all(tokens)
+    }
+}
+
+impl Tokenize for Tokenizer {
+    fn encode(&self, text: &str, bos: bool, eos: bool) -> Result<Vec<i32>> {
+        Tokenizer::encode(self, text, bos, eos)
+    }
+
+    fn encode_with_offsets(
+        &self,
+        text: &str,
+        bos: bool,
+        eos: bool,
+    ) -> Result<Vec<(i32, usize, usize)>> {
+        Tokenizer::encode_with_offsets(self, text, bos, eos)
+    }
+
+    fn encode_continuation(&self, text: &str) -> Result<Vec<i32>> {
+        let tokens = self.encode_spans(text, false, false, true)?;
+        Ok(tokens.into_iter().map(|(id, _, _)| id).collect())
+    }
+
+    fn piece_bytes(&self, token: i32) -> Option<Vec<u8>> {
+        Tokenizer::piece_bytes(self, token)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab.len()
+    }
+
+    fn token_id(&self, piece: &str) -> Option<i32> {
+        self.special_tokens
+            .get(piece)
+            .or_else(|| self.vocab_map.get(piece))
+            .copied()
+    }
+
+    fn special_tokens(&self) -> &HashMap<String, i32> {
+        &self.special_tokens
+    }
+
+    fn bos_id(&self) -> i32 {
+        self.bos_id
+    }
+
+    fn eos_id(&self) -> i32 {
+        self.eos_id
+    }
+
+    fn add_dummy_prefix(&self) -> bool {
+        self.add_dummy_prefix
+    }
+}
+
+/// Streaming detokenizer that reassembles UTF-8 split across byte tokens.
+///
+/// Follows SentencePiece decoding: the leading space of the piece right
+/// after BOS (the dummy prefix) is dropped, and control tokens produce no text.
+#[derive(Clone)]
+pub struct Detokenizer<'a> {
+    tokenizer: &'a dyn Tokenize,
+    buf: Vec<u8>,
+    prev: Option<i32>,
+}
+
+impl<'a> Detokenizer<'a> {
+    pub fn new(tokenizer: &'a dyn Tokenize) -> Self {
+        Detokenizer {
+            tokenizer,
+            buf: Vec::new(),
+            prev: None,
+        }
+    }
+
+    /// Add a token, returning the text completed by it. Bytes of a code point
+    /// that is still incomplete are held until the following tokens arrive.
+    pub fn push(&mut self, token: i32) -> String {
+        let mut bytes = self.tokenizer.piece_bytes(token).unwrap_or_default();
+        if self.tokenizer.add_dummy_prefix()
+            && self.prev == Some(self.tokenizer.bos_id())
+            && bytes.first() == Some(&b' ')
+        {
+            bytes.remove(0);
+        }
+        self.prev = Some(token);
+        self.buf.extend_from_slice(&bytes);
+
+        let mut out = String::new();
+        loop {
+            match std::str::from_utf8(&self.buf) {
+                Ok(s) => {
+                    out.push_str(s);
+                    self.buf.clear();
+                    break;
+                }
+                Err(e) => {
+                    let valid = e.valid_up_to();
+                    out.push_str(&String::from_utf8_lossy(&self.buf[..valid]));
+                    match e.error_len() {
+                        // Invalid sequence: replace it and keep going
+                        Some(len) => {
+                            out.push(char::REPLACEMENT_CHARACTER);
+                            self.buf.drain(..valid + len);
+                        }
+                        // Incomplete code point at the end: wait for more bytes
+                        None => {
+                            self.buf.drain(..valid);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Release held bytes of an unfinished code point as U+FFFD.
+    pub fn flush(&mut self) -> String {
+        let out = String::from_utf8_lossy(&self.buf).into_owned();
+        self.buf.clear();
+        out
+    }
+
+    /// Decode a whole token sequence, flushing at the end.
+    pub fn decode_all(mut self, tokens: &[i32]) -> String {
+        let mut text: String = tokens.iter().map(|&t| self.push(t)).collect();
+        text.push_str(&self.flush());
+        text
+    }
 }
 
+/// Parse a byte-fallback piece of the form `<0xNN>`.
+pub fn byte_token(piece: &str) -> Option<u8> {
+    if piece.len() == 6 && piece.starts_with("<0x") && piece.ends_with('>') {
+        u8::from_str_radix(&piece[3..5], 16).ok()
+    } else {
+        None
+    }
+}
+
+/// Beginning-of-sequence token id in llama2.c vocabularies.
+pub const BOS: i32 = 1;
+/// End-of-sequence token id in llama2.c vocabularies.
+pub const EOS: i32 = 2;
+
 /// Load tokenizer from a binary file.
 pub fn load_tokenizer<P: AsRef<Path>>(path: P, vocab_size: usize) -> Result<Tokenizer> {
     let file = File::open(path)?;
@@ -37,9 +469,8 @@ pub fn load_tokenizer<P: AsRef<Path>>(path: P, vocab_size: usize) -> Result<Toke
 
     let mut vocab = Vec::with_capacity(vocab_size);
     let mut scores = Vec::with_capacity(vocab_size);
-    let mut vocab_map = HashMap::with_capacity(vocab_size);
 
-    for i in 0..vocab_size {
+    for _ in 0..vocab_size {
         let score = reader.read_f32::<LittleEndian>()?;
         scores.push(score);
 
@@ -47,17 +478,70 @@ pub fn load_tokenizer<P: AsRef<Path>>(path: P, vocab_size: usize) -> Result<Toke
         let mut buf = vec![0u8; len];
         reader.read_exact(&mut buf)?;
 
-        let token = String::from_utf8_lossy(&buf).into_owned();
+        vocab.push(String::from_utf8_lossy(&buf).into_owned());
+    }
+
+    Ok(llama2c_tokenizer(vocab, scores, max_token_len))
+}
+
+/// Build a tokenizer with the llama2.c `tokenizer.bin` layout: `<unk>`, BOS
+/// and EOS at ids 0 to 2, then byte `b` at id `b + 3`.
+pub(crate) fn llama2c_tokenizer(
+    vocab: Vec<String>,
+    scores: Vec<f32>,
+    max_token_len: u32,
+) -> Tokenizer {
+    let mut vocab_map = HashMap::with_capacity(vocab.len());
+    for (i, token) in vocab.iter().enumerate() {
         vocab_map.insert(token.clone(), i as i32);
-        vocab.push(token);
     }
 
-    Ok(Tokenizer {
+    // Control pieces are exported as e.g. "\n<s>\n"
+    let special_tokens = vocab
+        .iter()
+        .take(3)
+        .enumerate()
+        .map(|(id, piece)| (piece.trim().to_string(), id as i32))
+        .filter(|(name, _)| matches!(name.as_str(), "<unk>" | "<s>" | "</s>"))
+        .collect();
+
+    Tokenizer {
         vocab,
         scores,
         vocab_map,
         max_token_len,
-    })
+        merges: HashMap::new(),
+        special_tokens,
+        bos_id: BOS,
+        eos_id: EOS,
+        unk_id: Some(0),
+        byte_tokens: (0..256).map(|b| b + 3).collect(),
+        add_dummy_prefix: true,
+        split_on_space: false,
+        remove_extra_whitespaces: false,
+    }
+}
+
+/// Write a tokenizer in the `tokenizer.bin` layout read by [`load_tokenizer`]:
+/// `max_token_len`, then a score, length and bytes for every token.
+///
+/// Byte-fallback tokens are written as their raw byte. Only a tokenizer with
+/// the llama2.c layout, e.g. one from [`load_tokenizer`] or the trainer, loads
+/// back unchanged.
+pub fn save_tokenizer<P: AsRef<Path>>(tokenizer: &Tokenizer, path: P) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_u32::<LittleEndian>(tokenizer.max_token_len)?;
+    for (id, (piece, &score)) in tokenizer.vocab.iter().zip(&tokenizer.scores).enumerate() {
+        let bytes = match tokenizer.byte_value(id as i32) {
+            Some(b) => vec![b],
+            None => piece.as_bytes().to_vec(),
+        };
+        writer.write_f32::<LittleEndian>(score)?;
+        writer.write_i32::<LittleEndian>(bytes.len() as i32)?;
+        writer.write_all(&bytes)?;
+    }
+    writer.flush()?;
+    Ok(())
 }
 
 /// BPE encode text, aligned with the C implementation.
@@ -73,7 +557,7 @@ pub fn bpe_encode(
 
     // Add BOS token if requested
     if bos {
-        tokens.push(1);
+        tokens.push(BOS);
     }
 
     // Add dummy prefix space if text is not empty (llama tokenizer behavior)
@@ -97,38 +581,469 @@ pub fn bpe_encode(
         }
     }
 
-    // Iteratively merge the best pair
-    loop {
-        let mut best_score = f32::NEG_INFINITY;
-        let mut best_id = -1i32;
-        let mut best_idx = None;
-
-        for i in 0..tokens.len().saturating_sub(1) {
-            let merged = format!(
-                "{}{}",
-                vocab[tokens[i] as usize],
-                vocab[tokens[i + 1] as usize]
-            );
-            if let Some(&id) = vocab_map.get(&merged) {
-                if scores[id as usize] > best_score {
-                    best_score = scores[id as usize];
-                    best_id = id;
-                    best_idx = Some(i);
+    let mut buf = String::new();
+    merge_pairs(&mut tokens, |a, b| {
+        buf.clear();
+        buf.push_str(&vocab[a as usize]);
+        buf.push_str(&vocab[b as usize]);
+        let &id = vocab_map.get(buf.as_str())?;
+        Some((scores[id as usize], id))
+    });
+
+    // Add EOS token if requested
+    if eos {
+        tokens.push(EOS);
+    }
+
+    Ok(tokens)
+}
+
+/// A candidate merge of two adjacent symbols.
+#[derive(Debug, PartialEq)]
+struct MergeCandidate {
+    score: f32,
+    left: usize,
+    right: usize,
+    left_id: i32,
+    right_id: i32,
+    merged: i32,
+}
+
+impl Eq for MergeCandidate {}
+
+impl Ord for MergeCandidate {
+    /// Highest score first; ties go to the leftmost pair.
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| other.left.cmp(&self.left))
+    }
+}
+
+impl PartialOrd for MergeCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Repeatedly merge the highest-scoring adjacent pair, in O(n log n).
+///
+/// `lookup` returns the score and resulting token of a pair, if it merges.
+/// Symbols form a doubly linked list over the initial positions and candidate
+/// pairs sit in a max-heap; entries made stale by an earlier merge are skipped
+/// when popped.
+pub(crate) fn merge_pairs(
+    tokens: &mut Vec<i32>,
+    lookup: impl FnMut(i32, i32) -> Option<(f32, i32)>,
+) {
+    merge_pairs_with_starts(tokens, &mut Vec::new(), lookup);
+}
+
+/// Merge like [`merge_pairs`], keeping `starts`, the start offset of each
+/// symbol, in step with the surviving symbols. `starts` may be empty.
+pub(crate) fn merge_pairs_with_starts(
+    tokens: &mut Vec<i32>,
+    starts: &mut Vec<usize>,
+    mut lookup: impl FnMut(i32, i32) -> Option<(f32, i32)>,
+) {
+    let n = tokens.len();
+    if n < 2 {
+        return;
+    }
+    let mut prev: Vec<usize> = (0..n).map(|i| i.wrapping_sub(1)).collect();
+    let mut next: Vec<usize> = (1..=n).collect();
+    let mut alive = vec![true; n];
+    let mut heap = BinaryHeap::new();
+
+    let mut push =
+        |heap: &mut BinaryHeap<MergeCandidate>, tokens: &[i32], left: usize, right: usize| {
+            let (left_id, right_id) = (tokens[left], tokens[right]);
+            if let Some((score, merged)) = lookup(left_id, right_id)
+                && score > f32::NEG_INFINITY
+            {
+                heap.push(MergeCandidate {
+                    score,
+                    left,
+                    right,
+                    left_id,
+                    right_id,
+                    merged,
+                });
+            }
+        };
+
+    for i in 0..n - 1 {
+        push(&mut heap, tokens, i, i + 1);
+    }
+
+    while let Some(c) = heap.pop() {
+        let stale = !alive[c.left]
+            || !alive[c.right]
+            || next[c.left] != c.right
+            || tokens[c.left] != c.left_id
+            || tokens[c.right] != c.right_id;
+        if stale {
+            continue;
+        }
+
+        tokens[c.left] = c.merged;
+        alive[c.right] = false;
+        next[c.left] = next[c.right];
+        if next[c.left] < n {
+            prev[next[c.left]] = c.left;
+        }
+
+        if prev[c.left] < n {
+            push(&mut heap, tokens, prev[c.left], c.left);
+        }
+        if next[c.left] < n {
+            push(&mut heap, tokens, c.left, next[c.left]);
+        }
+    }
+
+    let mut i = 0;
+    tokens.retain(|_| {
+        i += 1;
+        alive[i - 1]
+    });
+    if !starts.is_empty() {
+        let mut i = 0;
+        starts.retain(|_| {
+            i += 1;
+            alive[i - 1]
+        });
+    }
+}
+
+/// Split text at special token names, pairing each special part with its id.
+/// Where several names match at the same position, the longest wins.
+fn split_special<'t>(
+    text: &'t str,
+    special_tokens: &HashMap<String, i32>,
+) -> Vec<(&'t str, Option<i32>)> {
+    let mut first_bytes = [false; 256];
+    for name in special_tokens.keys().filter(|name| !name.is_empty()) {
+        first_bytes[name.as_bytes()[0] as usize] = true;
+    }
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let found = first_bytes[text.as_bytes()[i] as usize]
+            .then(|| {
+                special_tokens
+                    .iter()
+                    .filter(|(name, _)| !name.is_empty() && text[i..].starts_with(name.as_str()))
+                    .max_by_key(|(name, _)| name.len())
+            })
+            .flatten();
+        match found {
+            Some((name, &id)) => {
+                if start < i {
+                    parts.push((&text[start..i], None));
+                }
+                parts.push((&text[i..i + name.len()], Some(id)));
+                i += name.len();
+                start = i;
+            }
+            None => i += 1,
+        }
+    }
+    if start < text.len() {
+        parts.push((&text[start..], None));
+    }
+    parts
+}
+
+/// Split text before every space, keeping the spaces: `" a  b"` gives
+/// `[" a", " ", " b"]`, as the Metaspace pre-tokenizer does. Each part comes
+/// with its byte offset.
+fn split_before_spaces(text: &str) -> Vec<(usize, &str)> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if c == ' ' && i > start {
+            parts.push((start, &text[start..i]));
+            start = i;
+        }
+    }
+    if start < text.len() {
+        parts.push((start, &text[start..]));
+    }
+    parts
+}
+
+/// The Llama 2 tokenizer shipped with the repository, for tests.
+#[cfg(test)]
+pub(crate) fn llama2_tokenizer() -> Tokenizer {
+    load_tokenizer(concat!(env!("CARGO_MANIFEST_DIR"), "/tokenizer.bin"), 32000).unwrap()
+}
+
+/// Check that the token spans of `text` tile it in order and that each token
+/// spells exactly the bytes it spans. The dummy prefix spans nothing; with
+/// `collapse_spaces`, a run of spaces may stand for one and leading and
+/// trailing spaces belong to no token.
+#[cfg(test)]
+pub(crate) fn assert_spans_tile(tok: &dyn Tokenize, text: &str, collapse_spaces: bool) {
+    let (mut pos, mut end) = (0, text.len());
+    if collapse_spaces {
+        pos = text.len() - text.trim_start_matches(' ').len();
+        end = text.trim_end_matches(' ').len().max(pos);
+    }
+    let mut first = true;
+    for (id, start, stop) in tok.encode_with_offsets(text, true, true).unwrap() {
+        let mut piece = tok.piece_bytes(id).unwrap();
+        if piece.is_empty() {
+            assert_eq!(start, stop, "{text:?}: control token {id}");
+            continue;
+        }
+        if first && tok.add_dummy_prefix() {
+            assert_eq!(piece.remove(0), b' ', "{text:?}: dummy prefix");
+        }
+        first = false;
+        assert_eq!(
+            start, pos,
+            "{text:?}: token {id} does not follow the previous one"
+        );
+        let mut source = text.as_bytes()[start..stop].to_vec();
+        if collapse_spaces {
+            source.dedup_by(|a, b| *a == b' ' && *b == b' ');
+        }
+        assert_eq!(piece, source, "{text:?}: token {id} at {start}..{stop}");
+        pos = stop;
+    }
+    assert_eq!(pos, end, "{text:?}: spans end early");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continuation_has_no_dummy_prefix() {
+        let tok = llama2_tokenizer();
+        let colon = tok.token_id(":").unwrap();
+        assert_ne!(tok.encode(":", false, false).unwrap(), [colon]);
+        assert_eq!(tok.encode_continuation(":").unwrap(), [colon]);
+        let the = tok.token_id(" the").unwrap();
+        assert_eq!(tok.encode_continuation(" the").unwrap(), [the]);
+    }
+
+    #[test]
+    fn detokenizer_streams_split_utf8() {
+        let tok = llama2_tokenizer();
+        let mut detok = Detokenizer::new(&tok);
+        let bytes = "日".as_bytes();
+        let ids: Vec<i32> = bytes.iter().map(|&b| tok.byte_tokens[b as usize]).collect();
+        assert_eq!(detok.push(ids[0]), "");
+        assert_eq!(detok.push(ids[1]), "");
+        assert_eq!(detok.push(ids[2]), "日");
+
+        // An unfinished code point is released as a replacement character
+        assert_eq!(detok.push(ids[0]), "");
+        assert_eq!(detok.flush(), "\u{FFFD}");
+        // A stray continuation byte is replaced right away
+        assert_eq!(detok.push(ids[1]), "\u{FFFD}");
+    }
+
+    #[test]
+    fn detokenizer_strips_dummy_prefix_after_bos() {
+        let tok = llama2_tokenizer();
+        let ids = tok.encode("Hello world", true, false).unwrap();
+        assert_eq!(tok.decode_all(&ids), "Hello world");
+        // Only the piece right after BOS loses its space
+        assert_eq!(tok.decode_all(&ids[1..]), " Hello world");
+        assert_eq!(tok.decode_all(&[ids[0], tok.eos_id, ids[1]]), " Hello");
+    }
+
+    /// `bpe_encode` as it was before `merge_pairs`, kept verbatim: the
+    /// quadratic best-pair loop over characters and byte fallbacks.
+    #[allow(clippy::collapsible_if)]
+    fn baseline_bpe_encode(
+        text: &str,
+        vocab: &[String],
+        scores: &[f32],
+        vocab_map: &HashMap<String, i32>,
+        bos: bool,
+        eos: bool,
+    ) -> Result<Vec<i32>> {
+        let mut tokens: Vec<i32> = Vec::with_capacity(text.len() + 3);
+
+        // Add BOS token if requested
+        if bos {
+            tokens.push(1);
+        }
+
+        // Add dummy prefix space if text is not empty (llama tokenizer behavior)
+        if !text.is_empty() {
+            let dummy_prefix = vocab_map.get(" ").ok_or_else(|| {
+                LlamaError::Tokenizer("dummy prefix ' ' not found in vocabulary".into())
+            })?;
+            tokens.push(*dummy_prefix);
+        }
+
+        // Process text character by character
+        for c in text.chars() {
+            let char_str = c.to_string();
+            if let Some(&id) = vocab_map.get(&char_str) {
+                tokens.push(id);
+            } else {
+                // Byte-level fallback for unknown characters
+                for b in char_str.as_bytes() {
+                    tokens.push(*b as i32 + 3);
                 }
             }
         }
 
-        let Some(idx) = best_idx else { break };
+        // Iteratively merge the best pair
+        loop {
+            let mut best_score = f32::NEG_INFINITY;
+            let mut best_id = -1i32;
+            let mut best_idx = None;
 
-        // Merge the best pair
-        tokens[idx] = best_id;
-        tokens.remove(idx + 1);
+            for i in 0..tokens.len().saturating_sub(1) {
+                let merged = format!(
+                    "{}{}",
+                    vocab[tokens[i] as usize],
+                    vocab[tokens[i + 1] as usize]
+                );
+                if let Some(&id) = vocab_map.get(&merged) {
+                    if scores[id as usize] > best_score {
+                        best_score = scores[id as usize];
+                        best_id = id;
+                        best_idx = Some(i);
+                    }
+                }
+            }
+
+            let Some(idx) = best_idx else { break };
+
+            // Merge the best pair
+            tokens[idx] = best_id;
+            tokens.remove(idx + 1);
+        }
+
+        // Add EOS token if requested
+        if eos {
+            tokens.push(2);
+        }
+
+        Ok(tokens)
     }
 
-    // Add EOS token if requested
-    if eos {
-        tokens.push(2);
+    #[test]
+    fn merge_pairs_matches_naive_merging() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let tok = llama2_tokenizer();
+        // Rare CJK characters and emoji are missing from the vocabulary and
+        // fall back to bytes
+        let alphabet: Vec<char> = "aeinorst hlTHE  ,.'!?\n0123éüßΩжд日本語龘鱻🦀😀"
+            .chars()
+            .collect();
+        assert!(!tok.vocab_map.contains_key("龘") && !tok.vocab_map.contains_key("🦀"));
+        let mut rng = StdRng::seed_from_u64(42);
+        for i in 0..300 {
+            let len = rng.random_range(0..40);
+            let text: String = (0..len)
+                .map(|_| alphabet[rng.random_range(0..alphabet.len())])
+                .collect();
+            let (bos, eos) = (i % 2 == 0, i % 3 == 0);
+
+            let expected =
+                baseline_bpe_encode(&text, &tok.vocab, &tok.scores, &tok.vocab_map, bos, eos)
+                    .unwrap();
+            let merged =
+                bpe_encode(&text, &tok.vocab, &tok.scores, &tok.vocab_map, bos, eos).unwrap();
+            assert_eq!(merged, expected, "{text:?}");
+            let encoded = tok.encode(&text, bos, eos).unwrap();
+            assert_eq!(encoded, expected, "{text:?}");
+            assert_eq!(tok.decode_all(&encoded), tok.decode_all(&expected));
+        }
     }
 
-    Ok(tokens)
+    #[test]
+    fn spans_tile_the_text() {
+        let mut tok = llama2_tokenizer();
+        // llama2.c spells byte tokens as Latin-1 characters, so a byte
+        // fallback right after a space can merge into a piece such as " é"
+        // and no longer spell its own bytes; keep those apart here
+        let texts = [
+            "",
+            "Hello world, merged pieces!",
+            " leading space and  two",
+            "naïve 日本語龘🦀",
+            "\n\ttabs\n",
+        ];
+        // Whole words merge into one piece, rare characters fall back to bytes
+        let hello = tok.encode_with_offsets("Hello", false, false).unwrap();
+        assert_eq!(hello, [(tok.token_id(" Hello").unwrap(), 0, 5)]);
+        let crab = tok.encode_with_offsets("🦀", false, false).unwrap();
+        assert_eq!(crab.len(), 5);
+        assert_eq!(crab[0], (tok.token_id(" ").unwrap(), 0, 0));
+        assert_eq!(crab[4].1..crab[4].2, 3..4);
+
+        for text in texts {
+            assert_spans_tile(&tok, text, false);
+        }
+        tok.split_on_space = true;
+        for text in texts {
+            assert_spans_tile(&tok, text, false);
+        }
+    }
+
+    #[test]
+    fn spans_skip_removed_whitespace() {
+        let mut tok = llama2_tokenizer();
+        tok.remove_extra_whitespaces = true;
+        assert_eq!(
+            tok.encode("  Hello   world  ", false, false).unwrap(),
+            tok.encode("Hello world", false, false).unwrap()
+        );
+        for text in ["  Hello   big  world  ", "   ", "x", "a  日 本 "] {
+            assert_spans_tile(&tok, text, true);
+        }
+    }
+
+    #[test]
+    fn special_token_names_become_single_ids() {
+        let mut tok = llama2_tokenizer();
+        let (bos, eos) = (tok.bos_id, tok.eos_id);
+        assert_eq!(
+            split_special("日</s></s>é<s>", &tok.special_tokens),
+            [
+                ("日", None),
+                ("</s>", Some(eos)),
+                ("</s>", Some(eos)),
+                ("é", None),
+                ("<s>", Some(bos)),
+            ]
+        );
+
+        let plain = |text: &str| tok.encode(text, false, false).unwrap();
+        let mut expected = plain("Hi");
+        expected.extend([eos, eos]);
+        expected.extend(plain("日"));
+        expected.push(bos);
+        expected.extend(plain("é"));
+        assert_eq!(
+            tok.encode_with_special("Hi</s></s>日<s>é", false, false)
+                .unwrap(),
+            expected
+        );
+
+        // Without special parsing the names are ordinary text
+        let ids = tok.encode("Hi</s><s>", true, false).unwrap();
+        assert!(!ids.contains(&eos) && !ids[1..].contains(&bos));
+        assert_eq!(tok.decode_all(&ids), "Hi</s><s>");
+
+        // A registered vocabulary piece is matched the same way
+        let hello = tok.add_special_token("Hello").unwrap();
+        assert_eq!(
+            tok.encode_with_special("Hello</s>", true, false).unwrap(),
+            [bos, hello, eos]
+        );
+        assert!(tok.add_special_token("<|im_start|>").is_err());
+    }
 }
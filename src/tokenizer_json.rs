@@ -0,0 +1,325 @@
+//! Hugging Face `tokenizer.json` loading.
+//!
+//! Supports SentencePiece-style BPE models as shipped with Llama 2 and Mistral:
+//! a `BPE` model with merges and optional byte fallback, the `Prepend`/`Replace`
+//! normalizers, and the `Metaspace` pre-tokenizer.
+
+use crate::error::{LlamaError, Result};
+use crate::json::Json;
+use crate::tokenizer::{Tokenizer, byte_token};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// SentencePiece word-boundary marker, stored as a plain space in `Tokenizer` pieces.
+const METASPACE: char = '\u{2581}';
+
+/// Load a tokenizer from a Hugging Face `tokenizer.json` file.
+pub fn load_tokenizer_json<P: AsRef<Path>>(path: P) -> Result<Tokenizer> {
+    parse_tokenizer_json(&std::fs::read_to_string(path)?)
+}
+
+/// Build a tokenizer from the contents of a `tokenizer.json` file.
+pub fn parse_tokenizer_json(src: &str) -> Result<Tokenizer> {
+    let root = Json::parse(src)?;
+    let model = root.get("model").ok_or_else(|| tok_err("missing model"))?;
+    if let Some(ty) = model.get("type").and_then(Json::as_str)
+        && ty != "BPE"
+    {
+        return Err(tok_err(&format!("unsupported model type: {ty}")));
+    }
+    for key in ["continuing_subword_prefix", "end_of_word_suffix"] {
+        if model
+            .get(key)
+            .and_then(Json::as_str)
+            .is_some_and(|s| !s.is_empty())
+        {
+            return Err(tok_err(&format!("unsupported model option: {key}")));
+        }
+    }
+
+    // Vocabulary, including added tokens
+    let mut entries: Vec<(String, i32)> = Vec::new();
+    for (piece, id) in model
+        .get("vocab")
+        .and_then(Json::as_object)
+        .ok_or_else(|| tok_err("missing model.vocab"))?
+    {
+        entries.push((piece.clone(), as_id(id)?));
+    }
+    let mut special_tokens = HashMap::new();
+    for added in root
+        .get("added_tokens")
+        .and_then(Json::as_array)
+        .unwrap_or_default()
+    {
+        let content = added
+            .get("content")
+            .and_then(Json::as_str)
+            .ok_or_else(|| tok_err("added token without content"))?;
+        let id = as_id(added.get("id").unwrap_or(&Json::Null))?;
+        entries.push((content.to_string(), id));
+        if added.get("special").and_then(Json::as_bool) == Some(true) {
+            special_tokens.insert(content.to_string(), id);
+        }
+    }
+    let size = entries
+        .iter()
+        .map(|(_, id)| *id as usize + 1)
+        .max()
+        .unwrap_or(0);
+    // Ids missing from the file keep an empty piece and are left out of the
+    // lookup map, so no text ever encodes to them
+    let mut vocab = vec![String::new(); size];
+    let mut present = vec![false; size];
+    for (piece, id) in entries {
+        vocab[id as usize] = if special_tokens.get(&piece) == Some(&id) {
+            piece
+        } else {
+            piece.replace(METASPACE, " ")
+        };
+        present[id as usize] = true;
+    }
+    let mut vocab_map = HashMap::with_capacity(size);
+    for (id, piece) in vocab.iter().enumerate() {
+        if present[id] {
+            vocab_map.insert(piece.clone(), id as i32);
+        }
+    }
+
+    // Merges, either "a b" strings or [a, b] pairs, in priority order
+    let mut merges = HashMap::new();
+    for (rank, merge) in model
+        .get("merges")
+        .and_then(Json::as_array)
+        .unwrap_or_default()
+        .iter()
+        .enumerate()
+    {
+        let (a, b) = match merge {
+            Json::String(s) => s
+                .split_once(' ')
+                .ok_or_else(|| tok_err(&format!("invalid merge: {s}")))?,
+            Json::Array(pair) => match pair.as_slice() {
+                [Json::String(a), Json::String(b)] => (a.as_str(), b.as_str()),
+                _ => return Err(tok_err("invalid merge pair")),
+            },
+            _ => return Err(tok_err("invalid merge")),
+        };
+        let (a, b) = (a.replace(METASPACE, " "), b.replace(METASPACE, " "));
+        let merged = format!("{a}{b}");
+        if let (Some(&a), Some(&b), Some(&id)) =
+            (vocab_map.get(&a), vocab_map.get(&b), vocab_map.get(&merged))
+        {
+            merges.entry((a, b)).or_insert((rank as u32, id));
+        }
+    }
+
+    let byte_tokens = if model.get("byte_fallback").and_then(Json::as_bool) == Some(true) {
+        let mut ids = vec![0; 256];
+        for (piece, &id) in &vocab_map {
+            if let Some(b) = byte_token(piece) {
+                ids[b as usize] = id;
+            }
+        }
+        for (b, id) in ids.iter().enumerate() {
+            if vocab_map.get(&format!("<0x{b:02X}>")) != Some(id) {
+                return Err(tok_err(&format!("byte fallback token <0x{b:02X}> missing")));
+            }
+        }
+        ids
+    } else {
+        Vec::new()
+    };
+    let unk_id = model
+        .get("unk_token")
+        .and_then(Json::as_str)
+        .and_then(|unk| vocab_map.get(unk).copied());
+
+    let mut add_dummy_prefix = false;
+    let mut split_on_space = false;
+    if let Some(normalizer) = root.get("normalizer") {
+        apply_normalizer(normalizer, &mut add_dummy_prefix)?;
+    }
+    match root.get("pre_tokenizer") {
+        None | Some(Json::Null) => {}
+        Some(pre) if pre.get("type").and_then(Json::as_str) == Some("Metaspace") => {
+            let scheme = pre.get("prepend_scheme").and_then(Json::as_str);
+            let add_prefix = pre.get("add_prefix_space").and_then(Json::as_bool);
+            add_dummy_prefix |= match (scheme, add_prefix) {
+                (Some(scheme), _) => scheme != "never",
+                (None, add_prefix) => add_prefix.unwrap_or(true),
+            };
+            split_on_space = pre.get("split").and_then(Json::as_bool).unwrap_or(true);
+        }
+        Some(pre) => {
+            let ty = pre.get("type").and_then(Json::as_str).unwrap_or("?");
+            return Err(tok_err(&format!("unsupported pre-tokenizer: {ty}")));
+        }
+    }
+
+    let bos_id = template_bos(&root)
+        .or_else(|| find_special(&special_tokens, &["<s>", "<|begin_of_text|>"]))
+        .ok_or_else(|| tok_err("no BOS token"))?;
+    let eos_id = find_special(
+        &special_tokens,
+        &["</s>", "<|end_of_text|>", "<|endoftext|>", "<|im_end|>"],
+    )
+    .ok_or_else(|| tok_err("no EOS token"))?;
+
+    Ok(Tokenizer {
+        max_token_len: vocab.iter().map(|p| p.len() as u32).max().unwrap_or(0),
+        scores: vec![0.0; vocab.len()],
+        vocab,
+        vocab_map,
+        merges,
+        special_tokens,
+        bos_id,
+        eos_id,
+        unk_id,
+        byte_tokens,
+        add_dummy_prefix,
+        split_on_space,
+        remove_extra_whitespaces: false,
+    })
+}
+
+/// Check a normalizer, noting whether it prepends the word-boundary marker.
+fn apply_normalizer(normalizer: &Json, add_dummy_prefix: &mut bool) -> Result<()> {
+    if *normalizer == Json::Null {
+        return Ok(());
+    }
+    let ty = normalizer.get("type").and_then(Json::as_str).unwrap_or("?");
+    let metaspace = METASPACE.to_string();
+    match ty {
+        "Sequence" => {
+            for n in normalizer
+                .get("normalizers")
+                .and_then(Json::as_array)
+                .unwrap_or_default()
+            {
+                apply_normalizer(n, add_dummy_prefix)?;
+            }
+        }
+        "Prepend" if normalizer.get("prepend").and_then(Json::as_str) == Some(&metaspace) => {
+            *add_dummy_prefix = true;
+        }
+        // Spaces already stand for the marker in our pieces
+        "Replace"
+            if normalizer
+                .get("pattern")
+                .and_then(|p| p.get("String"))
+                .and_then(Json::as_str)
+                == Some(" ")
+                && normalizer.get("content").and_then(Json::as_str) == Some(&metaspace) => {}
+        _ => return Err(tok_err(&format!("unsupported normalizer: {ty}"))),
+    }
+    Ok(())
+}
+
+/// The special token that the post-processor template puts first, if any.
+fn template_bos(root: &Json) -> Option<i32> {
+    let post = root.get("post_processor")?;
+    let first = post.get("single")?.as_array()?.first()?;
+    let name = first.get("SpecialToken")?.get("id")?.as_str()?;
+    let ids = post
+        .get("special_tokens")?
+        .get(name)?
+        .get("ids")?
+        .as_array()?;
+    ids.first()?.as_f64().map(|id| id as i32)
+}
+
+fn find_special(special_tokens: &HashMap<String, i32>, names: &[&str]) -> Option<i32> {
+    names
+        .iter()
+        .find_map(|name| special_tokens.get(*name).copied())
+}
+
+fn as_id(value: &Json) -> Result<i32> {
+    match value.as_f64() {
+        Some(id) if id >= 0.0 && id.fract() == 0.0 => Ok(id as i32),
+        _ => Err(tok_err("token id must be a non-negative integer")),
+    }
+}
+
+fn tok_err(msg: &str) -> LlamaError {
+    LlamaError::Tokenizer(format!("tokenizer.json: {msg}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Llama-style BPE tokenizer: `Prepend`/`Replace` normalizers, merges
+    /// as strings, and id 21 missing from the vocabulary.
+    const FIXTURE: &str = r#"{
+        "added_tokens": [
+            {"id": 0, "content": "<unk>", "special": true},
+            {"id": 1, "content": "<s>", "special": true},
+            {"id": 2, "content": "</s>", "special": true},
+            {"id": 22, "content": "<|extra|>", "special": true}
+        ],
+        "normalizer": {"type": "Sequence", "normalizers": [
+            {"type": "Prepend", "prepend": "▁"},
+            {"type": "Replace", "pattern": {"String": " "}, "content": "▁"}
+        ]},
+        "pre_tokenizer": null,
+        "post_processor": {
+            "type": "TemplateProcessing",
+            "single": [{"SpecialToken": {"id": "<s>", "type_id": 0}}, {"Sequence": {"id": "A", "type_id": 0}}],
+            "special_tokens": {"<s>": {"id": "<s>", "ids": [1], "tokens": ["<s>"]}}
+        },
+        "model": {
+            "type": "BPE",
+            "unk_token": "<unk>",
+            "byte_fallback": false,
+            "vocab": {
+                "<unk>": 0, "<s>": 1, "</s>": 2, "▁": 3, "h": 4, "e": 5, "l": 6,
+                "o": 7, "w": 8, "r": 9, "d": 10, "▁h": 11, "ll": 12, "ell": 13,
+                "▁hell": 14, "▁hello": 15, "or": 16, "▁w": 17, "▁wor": 18,
+                "▁worl": 19, "▁world": 20
+            },
+            "merges": [
+                "▁ h", "l l", "e ll", "▁h ell", "▁hell o",
+                "o r", "▁ w", "▁w or", "▁wor l", "▁worl d"
+            ]
+        }
+    }"#;
+
+    #[test]
+    fn parses_fixture() {
+        let tok = parse_tokenizer_json(FIXTURE).unwrap();
+        assert_eq!(tok.vocab.len(), 23);
+        assert_eq!((tok.bos_id, tok.eos_id, tok.unk_id), (1, 2, Some(0)));
+        assert!(tok.add_dummy_prefix);
+        assert_eq!(tok.vocab[15], " hello");
+        assert_eq!(tok.special_tokens["<|extra|>"], 22);
+        assert!(!tok.vocab_map.contains_key(""));
+
+        assert_eq!(tok.encode("hello world", true, false).unwrap(), [1, 15, 20]);
+        assert_eq!(tok.encode("hold", false, true).unwrap(), [11, 7, 6, 10, 2]);
+        assert_eq!(tok.encode("hex", false, false).unwrap(), [11, 5, 0]);
+        assert_eq!(tok.decode_all(&[1, 15, 20, 2]), "hello world");
+    }
+
+    #[test]
+    fn byte_fallback_needs_every_byte() {
+        let bytes: String = (0..=255u32)
+            .map(|b| format!(r#", "<0x{b:02X}>": {}"#, b + 3))
+            .collect();
+        let src = format!(
+            r#"{{"added_tokens": [{{"id": 1, "content": "<s>", "special": true}},
+                {{"id": 2, "content": "</s>", "special": true}}],
+              "model": {{"type": "BPE", "byte_fallback": true,
+                "vocab": {{"<unk>": 0, "<s>": 1, "</s>": 2{bytes}, "a": 259}}}}}}"#
+        );
+        let tok = parse_tokenizer_json(&src).unwrap();
+        assert_eq!(
+            tok.encode("aé", false, false).unwrap(),
+            [259, 0xC3 + 3, 0xA9 + 3]
+        );
+
+        let partial = src.replace(r#", "<0x41>": 68"#, "");
+        assert!(parse_tokenizer_json(&partial).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Training BPE vocabularies for `tokenizer.bin`.
+
+use crate::error::{LlamaError, Result};
+use crate::tokenizer::{Tokenizer, llama2c_tokenizer};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Symbol for characters outside the covered set, which never merges.
+const UNCOVERED: u32 = u32::MAX;
+
+/// SentencePiece-style BPE training parameters.
+#[derive(Debug, Clone)]
+pub struct BpeTrainer {
+    /// Total number of tokens, including `<unk>`, BOS, EOS and the 256 byte tokens
+    pub vocab_size: usize,
+    /// Fraction of character occurrences that get their own token; the rarest
+    /// characters are left to byte fallback
+    pub character_coverage: f64,
+    /// Longest merged piece, in characters
+    pub max_piece_len: usize,
+}
+
+impl Default for BpeTrainer {
+    fn default() -> Self {
+        BpeTrainer {
+            vocab_size: 32000,
+            character_coverage: 0.9995,
+            max_piece_len: 16,
+        }
+    }
+}
+
+impl BpeTrainer {
+    /// Learn a vocabulary from `corpus`, one sentence per line.
+    ///
+    /// As in SentencePiece, each line gets a leading space and is split
+    /// before every space, so pieces never span words. The vocabulary is laid
+    /// out like llama2.c's: `<unk>`, BOS, EOS, the byte tokens, merged pieces
+    /// in the order they were learned (scored `0, -1, -2, ...`), and finally
+    /// the single characters.
+    pub fn train(&self, corpus: &str) -> Result<Tokenizer> {
+        let mut word_counts: HashMap<String, u64> = HashMap::new();
+        for line in corpus.lines().filter(|l| !l.is_empty()) {
+            let line = format!(" {line}");
+            let mut start = 0;
+            for (i, c) in line.char_indices() {
+                if c == ' ' && i > start {
+                    *word_counts.entry(line[start..i].to_string()).or_default() += 1;
+                    start = i;
+                }
+            }
+            *word_counts.entry(line[start..].to_string()).or_default() += 1;
+        }
+
+        // Characters by frequency, most common first, up to the coverage
+        let mut char_counts: HashMap<char, u64> = HashMap::new();
+        for (word, &count) in &word_counts {
+            for c in word.chars() {
+                *char_counts.entry(c).or_default() += count;
+            }
+        }
+        let mut chars: Vec<(char, u64)> = char_counts.into_iter().collect();
+        chars.sort_unstable_by_key(|&(c, count)| (Reverse(count), c));
+        let total: u64 = chars.iter().map(|&(_, count)| count).sum();
+        let mut covered = 0;
+        let mut n_chars = 0;
+        while n_chars < chars.len() && (covered as f64) < self.character_coverage * total as f64 {
+            covered += chars[n_chars].1;
+            n_chars += 1;
+        }
+        let mut pieces: Vec<String> = chars[..n_chars]
+            .iter()
+            .map(|&(c, _)| c.to_string())
+            .collect();
+        // The dummy prefix must always be a token
+        if !pieces.iter().any(|p| p == " ") {
+            pieces.push(" ".to_string());
+        }
+        let n_chars = pieces.len();
+
+        let n_fixed = 3 + 256;
+        let n_merges = self
+            .vocab_size
+            .checked_sub(n_fixed + n_chars)
+            .ok_or_else(|| {
+                LlamaError::Config(format!(
+                    "vocab size {} is too small for {n_fixed} fixed tokens and {n_chars} characters",
+                    self.vocab_size
+                ))
+            })?;
+
+        let mut piece_ids: HashMap<String, u32> = pieces
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (p.clone(), i as u32))
+            .collect();
+        let mut words: Vec<(Vec<u32>, u64)> = word_counts
+            .into_iter()
+            .map(|(word, count)| {
+                let symbols = word
+                    .chars()
+                    .map(|c| {
+                        let mut buf = [0u8; 4];
+                        piece_ids
+                            .get(&*c.encode_utf8(&mut buf))
+                            .copied()
+                            .unwrap_or(UNCOVERED)
+                    })
+                    .collect();
+                (symbols, count)
+            })
+            .collect();
+        words.sort_unstable();
+
+        // Pair counts, the words each pair occurs in, and a max-heap of
+        // (count, pair) entries that may be stale
+        let mut pair_counts: HashMap<(u32, u32), u64> = HashMap::new();
+        let mut pair_words: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+        for (w, (symbols, count)) in words.iter().enumerate() {
+            for pair in pairs(symbols) {
+                *pair_counts.entry(pair).or_default() += count;
+                pair_words.entry(pair).or_default().push(w);
+            }
+        }
+        let mut heap: BinaryHeap<(u64, Reverse<(u32, u32)>)> = pair_counts
+            .iter()
+            .map(|(&pair, &count)| (count, Reverse(pair)))
+            .collect();
+
+        let mut merged_pieces = Vec::with_capacity(n_merges);
+        let mut too_long = HashSet::new();
+        while merged_pieces.len() < n_merges {
+            let Some((count, Reverse(pair))) = heap.pop() else {
+                break;
+            };
+            if count == 0 || pair_counts.get(&pair) != Some(&count) || too_long.contains(&pair) {
+                continue;
+            }
+            let merged = format!("{}{}", pieces[pair.0 as usize], pieces[pair.1 as usize]);
+            if merged.chars().count() > self.max_piece_len {
+                too_long.insert(pair);
+                continue;
+            }
+            let id = match piece_ids.get(&merged) {
+                Some(&id) => id,
+                None => {
+                    let id = pieces.len() as u32;
+                    piece_ids.insert(merged.clone(), id);
+                    pieces.push(merged);
+                    merged_pieces.push(id);
+                    id
+                }
+            };
+
+            // Re-count the pairs of every word the merge touches
+            let mut changed = HashSet::new();
+            let mut affected = pair_words.remove(&pair).unwrap_or_default();
+            affected.sort_unstable();
+            affected.dedup();
+            for w in affected {
+                let (symbols, count) = &mut words[w];
+                if !pairs(symbols).any(|p| p == pair) {
+                    continue;
+                }
+                for p in pairs(symbols) {
+                    *pair_counts.get_mut(&p).unwrap() -= *count;
+                    changed.insert(p);
+                }
+                merge_word(symbols, pair, id);
+                for p in pairs(symbols) {
+                    *pair_counts.entry(p).or_default() += *count;
+                    changed.insert(p);
+                    if p.0 == id || p.1 == id {
+                        pair_words.entry(p).or_default().push(w);
+                    }
+                }
+            }
+            for p in changed {
+                match pair_counts[&p] {
+                    0 => {
+                        pair_counts.remove(&p);
+                    }
+                    count => heap.push((count, Reverse(p))),
+                }
+            }
+        }
+        if merged_pieces.len() < n_merges {
+            return Err(LlamaError::Config(format!(
+                "corpus only supports a vocab size of {}",
+                n_fixed + n_chars + merged_pieces.len()
+            )));
+        }
+
+        let mut vocab: Vec<String> = vec!["<unk>".into(), "\n<s>\n".into(), "\n</s>\n".into()];
+        vocab.extend((0..=255u8).map(|b| String::from_utf8_lossy(&[b]).into_owned()));
+        let mut scores = vec![0.0; vocab.len()];
+        let learned = merged_pieces.iter().copied().chain(0..n_chars as u32);
+        for (rank, id) in learned.enumerate() {
+            vocab.push(pieces[id as usize].clone());
+            scores.push(-(rank as f32));
+        }
+        // Byte tokens are a single raw byte in the file
+        let max_token_len = vocab
+            .iter()
+            .enumerate()
+            .map(|(id, p)| {
+                if (3..n_fixed).contains(&id) {
+                    1
+                } else {
+                    p.len()
+                }
+            })
+            .max()
+            .unwrap_or(0) as u32;
+        Ok(llama2c_tokenizer(vocab, scores, max_token_len))
+    }
+}
+
+/// Adjacent symbol pairs of a word, skipping uncovered characters.
+fn pairs(symbols: &[u32]) -> impl Iterator<Item = (u32, u32)> + '_ {
+    symbols
+        .windows(2)
+        .map(|w| (w[0], w[1]))
+        .filter(|&(a, b)| a != UNCOVERED && b != UNCOVERED)
+}
+
+/// Replace each occurrence of `pair`, left to right, with `id`.
+fn merge_word(symbols: &mut Vec<u32>, pair: (u32, u32), id: u32) {
+    let mut out = Vec::with_capacity(symbols.len());
+    let mut i = 0;
+    while i < symbols.len() {
+        if i + 1 < symbols.len() && (symbols[i], symbols[i + 1]) == pair {
+            out.push(id);
+            i += 2;
+        } else {
+            out.push(symbols[i]);
+            i += 1;
+        }
+    }
+    *symbols = out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::{load_tokenizer, save_tokenizer};
+
+    const CORPUS: &str = "the cat sat on the mat\n\
+                          the dog sat on the log\n\
+                          a cat and a dog met on the mat\n\
+                          the 🦀 sat\n";
+
+    fn trainer(vocab_size: usize) -> BpeTrainer {
+        BpeTrainer {
+            vocab_size,
+            character_coverage: 0.98,
+            max_piece_len: 8,
+        }
+    }
+
+    #[test]
+    fn trained_tokenizer_round_trips_through_a_file() {
+        let tok = trainer(290).train(CORPUS).unwrap();
+        assert_eq!(tok.vocab.len(), 290);
+        // The rare crab is left to byte fallback
+        assert!(!tok.vocab_map.contains_key("🦀"));
+        assert!(tok.vocab_map.contains_key(" the"));
+
+        let path = std::env::temp_dir().join(format!("trainer-{}.bin", std::process::id()));
+        save_tokenizer(&tok, &path).unwrap();
+        let loaded = load_tokenizer(&path, 290);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.vocab.len(), 290);
+        assert_eq!(loaded.scores, tok.scores);
+        assert_eq!(loaded.vocab[259..], tok.vocab[259..]);
+        for text in ["the cat sat on the log", "a 🦀 met the dog", "🦀", "x"] {
+            let ids = loaded.encode(text, true, false).unwrap();
+            assert_eq!(ids, tok.encode(text, true, false).unwrap(), "{text:?}");
+            assert_eq!(loaded.decode_all(&ids), text);
+        }
+        let crab = loaded.encode("🦀", false, false).unwrap();
+        assert_eq!(
+            crab[1..],
+            "🦀".bytes().map(|b| b as i32 + 3).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn rejects_vocab_sizes_that_cannot_fit() {
+        // The 259 fixed tokens alone exceed it
+        assert!(trainer(200).train(CORPUS).is_err());
+        // Room for the fixed tokens but not every covered character
+        assert!(trainer(262).train(CORPUS).is_err());
+        // More merges than the corpus has pairs
+        assert!(trainer(5000).train(CORPUS).is_err());
+    }
+}
@@ -105,3 +105,37 @@ fn read_f32_vec<R: Read>(reader: &mut R, count: usize) -> Result<Vec<f32>> {
     }
     Ok(buf)
 }
+
+#[cfg(test)]
+impl LlamaWeights {
+    /// Small random weights for tests.
+    pub(crate) fn random(config: &LlamaConfig, seed: u64) -> Self {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut rand_vec =
+            |n: usize| -> Vec<f32> { (0..n).map(|_| rng.random_range(-0.5..0.5)).collect() };
+        let dim = config.dim as usize;
+        let hdim = config.hidden_dim as usize;
+        let kv_dim = config.kv_dim();
+        let layers = (0..config.n_layers)
+            .map(|_| LlamaLayerWeights {
+                attn_norm: vec![1.0; dim],
+                q_proj: rand_vec(dim * dim),
+                k_proj: rand_vec(dim * kv_dim),
+                v_proj: rand_vec(dim * kv_dim),
+                o_proj: rand_vec(dim * dim),
+                ffn_norm: vec![1.0; dim],
+                gate_proj: rand_vec(hdim * dim),
+                up_proj: rand_vec(hdim * dim),
+                down_proj: rand_vec(dim * hdim),
+            })
+            .collect();
+        LlamaWeights {
+            embed_tokens: rand_vec(config.vocab_size as usize * dim),
+            layers,
+            norm: vec![1.0; dim],
+        }
+    }
+}